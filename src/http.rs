@@ -0,0 +1,268 @@
+// Minimal local HTTP API keeping the device open, requests are handled one at a time and
+// dispatched to the JSON-RPC methods
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use rusb::{Error, Result, UsbContext};
+use rustar::protocol::Dpi;
+use serde_json::{Value, json};
+
+use crate::Mouse;
+use crate::rpc::{self, RpcError};
+
+// Largest request body accepted, the API only takes small JSON objects
+const MAX_BODY: usize = 4096;
+// Largest request line and headers accepted
+const MAX_HEAD: usize = 8192;
+// How long a client gets to send its whole request, requests are served one at a time so a slow
+// client would otherwise block every other one, /shutdown included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+// What was written during the session, the device can't be read back
+struct Written {
+    dpi: Vec<Option<u16>>,
+    active: Option<u8>,
+    count: Option<u8>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    host: Option<String>,
+    origin: Option<String>,
+    content_type: Option<String>,
+    body: Value,
+}
+
+// Reads from the stream until a deadline shared by every read, so trickling bytes can't keep a
+// request open past REQUEST_TIMEOUT
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    until: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.until.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
+
+// Serves requests until POST /shutdown, stops early only when the mouse is unplugged
+pub fn serve<T: UsbContext>(mouse: &mut Mouse<T>, addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).map_err(|e| {
+        error!("Failed to listen on {}: {}", addr, e);
+        Error::Io
+    })?;
+    if !addr.ip().is_loopback() {
+        warning!("Warning: listening on {}, anyone who can reach it can change the mouse settings", addr);
+    }
+    success!("Listening on http://{}", addr);
+
+    // Browsers send the Host they resolved and the Origin of the page, anything else than this
+    // server means a cross-origin or DNS rebinding request
+    let mut hosts = vec![addr.to_string()];
+    if addr.ip().is_loopback() {
+        hosts.push(format!("localhost:{}", addr.port()));
+    }
+
+    let mut written = Written { dpi: vec![None; mouse.max_profiles as usize], active: None, count: None };
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+        if stream.set_write_timeout(Some(REQUEST_TIMEOUT)).is_err() {
+            continue;
+        }
+
+        let (status, body) = match read_request(&stream).and_then(|request| check(request, &hosts)) {
+            Ok(request) if request.method == "POST" && request.path == "/shutdown" => {
+                respond(&mut stream, 200, &json!({ "shutdown": true }));
+                return Ok(());
+            },
+            Ok(request) => route(mouse, &mut written, &request),
+            Err((status, message)) => (status, json!({ "error": message })),
+        };
+        respond(&mut stream, status, &body);
+
+        if status == 503 {
+            return Err(Error::NoDevice);
+        }
+    }
+
+    Ok(())
+}
+
+// Rejects requests a browser could send on behalf of another site
+fn check(request: Request, hosts: &[String]) -> std::result::Result<Request, (u16, String)> {
+    match &request.host {
+        Some(host) if hosts.contains(host) => {},
+        Some(host) => return Err((403, format!("Unexpected Host '{}'", host))),
+        None => return Err((400, "Missing Host header".to_string())),
+    }
+    if let Some(origin) = &request.origin
+        && !hosts.iter().any(|host| *origin == format!("http://{}", host))
+    {
+        return Err((403, format!("Cross-origin requests from '{}' are not allowed", origin)));
+    }
+    // Forms can only send other types, which keeps them from reaching the device
+    let json = request
+        .content_type
+        .as_deref()
+        .is_some_and(|t| t.split(';').next().is_some_and(|t| t.trim().eq_ignore_ascii_case("application/json")));
+    if request.method == "POST" && !json {
+        return Err((415, "POST requests must be sent as application/json".to_string()));
+    }
+
+    Ok(request)
+}
+
+fn route<T: UsbContext>(mouse: &mut Mouse<T>, written: &mut Written, request: &Request) -> (u16, Value) {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["profiles"]) => {
+            let profiles: Vec<Value> = written
+                .dpi
+                .iter()
+                .enumerate()
+                .map(|(profile, dpi)| json!({ "profile": profile, "dpi": dpi }))
+                .collect();
+            return (200, json!({ "profiles": profiles, "active": written.active, "count": written.count }));
+        },
+        ("POST", ["profile", profile, "dpi"]) => {
+            let Ok(profile) = profile.parse::<u8>() else {
+                return (404, json!({ "error": format!("No profile '{}'", profile) }));
+            };
+            let params = json!({ "profile": profile, "dpi": request.body.get("dpi") });
+            let clamp = mouse.clamp;
+            rpc::call(mouse, "setDpi", &params).inspect(|_| {
+                let dpi = match &params["dpi"] {
+                    Value::String(s) => s.parse::<Dpi>().ok(),
+                    value => value.as_u64().map(|dpi| Dpi(u16::try_from(dpi).unwrap_or(u16::MAX))),
+                };
+                let dpi = dpi.map(|dpi| if clamp { dpi.clamp().0 } else { dpi.0 });
+                if let Some(slot) = written.dpi.get_mut(profile as usize) {
+                    *slot = dpi;
+                }
+            })
+        },
+        ("POST", ["profile", profile, "select"]) => {
+            let Ok(profile) = profile.parse::<u8>() else {
+                return (404, json!({ "error": format!("No profile '{}'", profile) }));
+            };
+            rpc::call(mouse, "selectProfile", &json!({ "profile": profile }))
+                .inspect(|_| written.active = Some(profile))
+        },
+        ("POST", ["profiles"]) => {
+            let count = request.body.get("count").cloned().unwrap_or(Value::Null);
+            rpc::call(mouse, "setProfilesCount", &json!({ "count": count }))
+                .inspect(|_| written.count = count.as_u64().map(|count| count as u8))
+        },
+        _ => return (404, json!({ "error": format!("No route for {} {}", request.method, request.path) })),
+    };
+
+    match result {
+        Ok(value) => (200, value),
+        Err(e) => (status(&e), json!({ "error": e.message })),
+    }
+}
+
+fn status(e: &RpcError) -> u16 {
+    match (e.code, e.usb) {
+        (_, Some(Error::NoDevice)) => 503,
+        (rpc::DEVICE_ERROR, _) => 502,
+        (rpc::METHOD_NOT_FOUND, _) => 404,
+        _ => 400,
+    }
+}
+
+// Fails with the status to answer and why
+fn read_request(stream: &TcpStream) -> std::result::Result<Request, (u16, String)> {
+    let deadline = Deadline { stream, until: Instant::now() + REQUEST_TIMEOUT };
+    let mut reader = BufReader::new(deadline.take((MAX_HEAD + MAX_BODY) as u64));
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(io_error)?;
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err((400, "Malformed request line".to_string()));
+    };
+    let (method, path) = (method.to_string(), path.to_string());
+
+    let (mut length, mut host, mut origin, mut content_type) = (0, None, None, None);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).map_err(io_error)? == 0 {
+            return Err((400, "Truncated request headers".to_string()));
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.to_ascii_lowercase().as_str() {
+            "content-length" => {
+                length = value.parse().map_err(|_| (400, "Invalid Content-Length".to_string()))?;
+            },
+            "host" => host = Some(value.to_string()),
+            "origin" => origin = Some(value.to_string()),
+            "content-type" => content_type = Some(value.to_string()),
+            _ => {},
+        }
+    }
+    if length > MAX_BODY {
+        return Err((400, format!("Body larger than {} bytes", MAX_BODY)));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).map_err(io_error)?;
+    let body = if body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&body).map_err(|e| (400, e.to_string()))?
+    };
+
+    Ok(Request { method, path, host, origin, content_type, body })
+}
+
+fn io_error(e: std::io::Error) -> (u16, String) {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => (408, "Timed out reading the request".to_string()),
+        _ => (400, e.to_string()),
+    }
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        408 => "Request Timeout",
+        415 => "Unsupported Media Type",
+        502 => "Bad Gateway",
+        _ => "Service Unavailable",
+    };
+    let body = body.to_string();
+
+    // The client may already be gone, there is nobody to report the failure to
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        body.len(),
+        body
+    );
+}
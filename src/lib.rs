@@ -0,0 +1,5 @@
+//! Helpers for the Pulsar X2v2 family of mice: report encoding in [`protocol`], which needs no
+//! USB access, and interface handling in [`usb`].
+
+pub mod protocol;
+pub mod usb;
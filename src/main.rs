@@ -1,16 +1,223 @@
-use std::time::Duration;
+#[macro_use]
+mod output;
+mod http;
+mod rpc;
+mod table;
 
-use clap::{Parser, Subcommand};
-use rusb::{Context, Device, DeviceHandle, Error, Result, UsbContext};
+use std::io::{IsTerminal, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use clap::parser::ValueSource;
+use clap::builder::PossibleValuesParser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum, ValueHint};
+use rustar::protocol::{self, Checksum, Dpi, ReportFormat, MAX_DPI, MIN_DPI, REPORT_ID, REPORT_LEN};
+use rustar::usb;
+use rusb::{Context, Device, DeviceHandle, Direction, Error, Result, Speed, TransferType, UsbContext};
 
 const VID: u16 = 0x3554;
 const PID: u16 = 0xf509;
+// How long --wait keeps retrying a busy device
+const BUSY_WAIT: Duration = Duration::from_secs(5);
+// Default timeout of USB transfers, in milliseconds
+const TIMEOUT_MS: u64 = 1000;
+// SET_REPORT class request to the interface
+const REQUEST_TYPE: u8 = 0x21;
+const REQUEST: u8 = 0x09;
+// Output report type in the high byte of the SET_REPORT value, report ID in the low one
+const REPORT_TYPE: u16 = 0x0200;
+// Largest buffer read_interrupt grows to when reports overflow
+const READ_MAX: usize = 1024;
+// Interfaces detached from the kernel driver and claimed for the whole run, when the device has them
+const INTERFACES: [u8; 2] = [0, 1];
+// Interface the feature reports are addressed to
+const REPORT_INTERFACE: u8 = 1;
+
+// Amount of profiles assumed for models missing from KNOWN_MODELS
+const MAX_PROFILES: u8 = 4;
+// Exit code used when the mouse is unplugged while a command runs
+const EXIT_DISCONNECTED: i32 = 3;
+
+struct Model {
+    name: &'static str,
+    vid: u16,
+    pid: u16,
+    // Length of the feature reports expected by the firmware, reports are zero padded up to it
+    report_len: usize,
+    // Amount of onboard DPI profiles the firmware supports
+    max_profiles: u8,
+}
+
+// Every model we know how to talk to, probed in order when no PID is given
+const KNOWN_MODELS: &[Model] = &[
+    Model { name: "Pulsar X2v2", vid: VID, pid: PID, report_len: REPORT_LEN, max_profiles: 4 },
+];
+
+// Counters of the transfers sent during a run, printed with --stats
+#[derive(Default)]
+struct Stats {
+    transfers: usize,
+    bytes: usize,
+}
+
+// An opened mouse along with the shape of the reports it expects
+pub(crate) struct Mouse<T: UsbContext> {
+    // Released and re-attached when dropped, even on early returns. None on a dry run without a
+    // device, see --allow-absent
+    handle: Option<usb::Claimed<DeviceHandle<T>>>,
+    format: ReportFormat,
+    // Pause between two consecutive transfers, some firmware drops reports sent back-to-back
+    delay: Duration,
+    timeout_write: Duration,
+    timeout_read: Duration,
+    // Print every report instead of sending it
+    dry_run: bool,
+    // Print every report before sending it
+    verbose: bool,
+    // Interface the reports are addressed to
+    interface: u8,
+    // Amount of profiles supported by the model
+    max_profiles: u8,
+    // Skip the range checks, set by --no-validate
+    no_validate: bool,
+    // Bring out-of-range DPI values within limits, set by --clamp
+    clamp: bool,
+    stats: Stats,
+    // Every report sent, back to back, written out by --dump-last
+    sent: Vec<u8>,
+    // Read and print what the device answers after a failed transfer
+    report_raw_on_error: bool,
+    // Ask before sending every report
+    confirm_writes: bool,
+}
+
+impl<T: UsbContext> Mouse<T> {
+    fn handle(&mut self) -> &mut usb::Claimed<DeviceHandle<T>> {
+        self.handle.as_mut().expect("the device is only absent on a dry run")
+    }
+}
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Args {
+    /// USB vendor ID of the mouse
+    #[arg(long, global = true, env = "RUSTAR_VID", value_parser = parse_u16, default_value_t = VID)]
+    vid: u16,
+    /// USB product ID of the mouse
+    #[arg(long, global = true, env = "RUSTAR_PID", value_parser = parse_u16, conflicts_with = "pid_list")]
+    pid: Option<u16>,
+    /// Comma separated product IDs to try in order, the first one found is used
+    #[arg(long, global = true, env = "RUSTAR_PID_LIST", value_parser = parse_u16, value_delimiter = ',')]
+    pid_list: Vec<u16>,
+    /// Open this device node (e.g. /dev/bus/usb/001/005) directly instead of enumerating devices
+    #[arg(long, global = true, value_hint = ValueHint::FilePath)]
+    path: Option<PathBuf>,
+    /// Select the device with this serial number, stable across reboots unlike bus addresses
+    #[arg(long, global = true, conflicts_with = "path")]
+    serial: Option<String>,
+    /// Select the Nth matching device (0-based, as printed by `list`)
+    #[arg(long, global = true, conflicts_with = "path")]
+    device_index: Option<usize>,
+    /// Remember the serial number of the selected device, later runs select it by default
+    #[arg(long, global = true, conflicts_with = "forget")]
+    remember: bool,
+    /// Forget the device saved with --remember
+    #[arg(long, global = true)]
+    forget: bool,
+    /// Checksum algorithm used when building reports, for reverse engineering other models
+    #[arg(long, global = true, value_enum, default_value_t = Checksum::Device)]
+    payload_checksum: Checksum,
+    /// Switch to this profile once the command succeeded
+    #[arg(long, global = true)]
+    after_select: Option<u8>,
+    /// Layout of tabular output
+    #[arg(long, global = true, value_enum, default_value_t = table::Format::Table)]
+    format: table::Format,
+    /// Format of errors, warnings and progress messages
+    #[arg(long, global = true, value_enum, default_value_t = output::LogFormat::Text)]
+    log_format: output::LogFormat,
+    /// Only print errors, warnings and requested output
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Shell command run after a successful command, with the applied setting in RUSTAR_*
+    /// environment variables. It runs with your privileges, only pass commands you trust
+    #[arg(long, global = true, value_hint = ValueHint::CommandString)]
+    post_command_hook: Option<String>,
+    /// When a transfer fails, read the report interface's interrupt endpoint and print what the
+    /// device answered, if anything
+    #[arg(long, global = true)]
+    report_raw_on_error: bool,
+    /// Print every report and ask whether to send it, declined reports are skipped
+    #[arg(long, global = true, conflicts_with = "dry_run")]
+    confirm_writes: bool,
+    /// Print how many transfers were sent and how long it took
+    #[arg(long, global = true)]
+    stats: bool,
+    /// Write the bytes of every report sent to this file, back to back, for archiving or replay
+    #[arg(long, global = true, value_name = "PATH", value_hint = ValueHint::FilePath)]
+    dump_last: Option<PathBuf>,
+    /// Length the reports are zero padded to, for firmware expecting longer reports than the
+    /// detected model
+    #[arg(long, global = true, value_parser = parse_report_len)]
+    report_len: Option<usize>,
+    /// Report ID leading every report, also sent as the low byte of the SET_REPORT value
+    #[arg(long, global = true, value_parser = parse_u8, default_value_t = REPORT_ID)]
+    report_id: u8,
+    /// Milliseconds to wait between consecutive transfers, longer delays are more reliable but slower
+    #[arg(long, global = true, default_value_t = 20)]
+    delay_ms: u64,
+    /// Milliseconds to wait for a sent report to be accepted
+    #[arg(long, global = true, default_value_t = TIMEOUT_MS)]
+    timeout_write: u64,
+    /// Milliseconds to wait for the device to answer a read, which may need longer than writes
+    #[arg(long, global = true, default_value_t = TIMEOUT_MS)]
+    timeout_read: u64,
+    /// Clamp out-of-range DPI values to the supported range instead of failing
+    #[arg(long, global = true)]
+    clamp: bool,
+    /// Interface the feature reports are sent to and claimed for, for models with another layout
+    #[arg(long, global = true, default_value_t = REPORT_INTERFACE)]
+    interface: u8,
+    /// Allow writing to devices missing from the known models
+    #[arg(long, global = true)]
+    force: bool,
+    /// Skip the profile and DPI range checks, for probing the device beyond its documented limits
+    #[arg(long, global = true)]
+    no_validate: bool,
+    /// Print the reports that would be sent without sending them
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// Print every report along with its checksum before sending it
+    #[arg(short, long, global = true)]
+    verbose: bool,
+    /// Keep retrying for a few seconds when the device is busy
+    #[arg(long, global = true)]
+    wait: bool,
+    /// Don't detach the kernel driver from the interfaces before claiming them
+    #[arg(long, global = true)]
+    no_detach: bool,
+    /// Don't re-attach the kernel driver on exit, the mouse may stay unresponsive until replugged
+    #[arg(long, global = true)]
+    no_reattach: bool,
+    /// Warn when the device's interface layout differs from the model its IDs belong to, as
+    /// rebadged mice reusing IDs may behave differently
+    #[arg(long, global = true)]
+    warn_on_rebadge: bool,
+    /// With --dry-run, print the reports even when no device is found, for the model given by
+    /// --pid or the first known one
+    #[arg(long, global = true, requires = "dry_run")]
+    allow_absent: bool,
+    /// Debugging aid: don't release the interfaces nor re-attach the kernel driver on exit, the
+    /// mouse may stay unresponsive until replugged
+    #[arg(long, global = true)]
+    no_cleanup: bool,
+    /// Print the resolved settings and where each one comes from, then exit
+    #[arg(long, global = true)]
+    show_config: bool,
+
     #[command(subcommand)]
-    cmd: Commands
+    cmd: Option<Commands>
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -23,169 +230,1196 @@ enum Commands {
         #[arg()]
         profile: u8,
     },
+    /// Set a profile's DPI with `set <PROFILE> <DPI>`, or several settings with `set --profile <PROFILE> key=value...`
     Set {
+        /// Profile then DPI value in decimal or 0x prefixed hexadecimal, or a preset name (low, medium,
+        /// high, max). Or key=value pairs, feature-set lists the keys
+        #[arg(required = true, value_name = "ARGS")]
+        values: Vec<String>,
+        /// Profile the per-profile key=value settings apply to
+        #[arg(long)]
+        profile: Option<u8>,
+    },
+    /// Show where the mouse sits on the USB bus and its interface layout
+    Info,
+    /// List the matching devices along with their index
+    List,
+    /// Show what the connected model supports
+    Capabilities,
+    /// Serve newline-delimited JSON-RPC requests on stdin (setDpi, selectProfile, setProfilesCount)
+    Rpc,
+    /// Serve a local HTTP API: GET /profiles, POST /profile/{n}/dpi, POST /profile/{n}/select,
+    /// POST /profiles and POST /shutdown
+    Serve {
+        /// Address to listen on, only reachable from this machine by default
+        #[arg(default_value = "127.0.0.1:8080")]
+        addr: SocketAddr,
+    },
+    /// Send the reports of a file written by --dump-last, as is
+    Replay {
+        #[arg(value_hint = ValueHint::FilePath)]
+        path: PathBuf,
+        /// bmRequestType of the transfers
+        #[arg(long, value_parser = parse_u8, default_value_t = REQUEST_TYPE)]
+        request_type: u8,
+        /// wValue of the transfers, defaults to an output report with --report-id
+        #[arg(long, value_parser = parse_u16)]
+        value: Option<u16>,
+        /// wIndex of the transfers, defaults to --interface
+        #[arg(long, value_parser = parse_u16)]
+        index: Option<u16>,
+    },
+    /// Print the report a setting would be written with, no device needed
+    Encode {
+        #[arg(requires = "value", value_parser = setting_names(), ignore_case = true)]
+        name: Option<String>,
         #[arg()]
-        profile: u8,
+        value: Option<String>,
+        /// Profile the setting applies to, for per-profile settings
+        #[arg(long)]
+        profile: Option<u8>,
+    },
+    /// Write a setting by name, run without arguments to list the settings
+    FeatureSet {
+        #[arg(requires = "value", value_parser = setting_names(), ignore_case = true)]
+        name: Option<String>,
         #[arg()]
-        value: u16,
+        value: Option<String>,
+        /// Profile the setting applies to, for per-profile settings
+        #[arg(long)]
+        profile: Option<u8>,
+    },
+}
+
+impl Commands {
+    // Whether the command sends reports to the mouse
+    fn writes(&self) -> bool {
+        !matches!(self, Commands::Info | Commands::List | Commands::Capabilities | Commands::Encode { .. })
     }
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let start = Instant::now();
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    output::set_log_format(args.log_format);
+
+    // Explicit PIDs first, then fall back to probing every known model for this vendor
+    let mut pids: Vec<u16> = match args.pid {
+        Some(pid) => vec![pid],
+        None => args.pid_list.clone(),
+    };
+    if pids.is_empty() {
+        pids.extend(KNOWN_MODELS.iter().filter(|m| m.vid == args.vid).map(|m| m.pid));
+    }
+
+    if args.show_config {
+        show_config(&args, &matches, &pids);
+        return Ok(());
+    }
+
+    if args.forget {
+        if let Some(path) = remembered_path()
+            && let Err(e) = std::fs::remove_file(&path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            warning!("Warning: failed to remove {}: {}", path.display(), e);
+        }
+        if args.cmd.is_none() {
+            success!("Forgot the remembered device");
+            return Ok(());
+        }
+    }
+
+    let Some(cmd) = args.cmd else {
+        Args::command().print_help().ok();
+        std::process::exit(2);
+    };
+    // The prompt needs a terminal to answer from, which RPC mode uses for requests
+    if args.confirm_writes && (matches!(cmd, Commands::Rpc) || !std::io::stdin().is_terminal()) {
+        error!("--confirm-writes needs an interactive terminal on stdin");
+        std::process::exit(1);
+    }
+    // stdout only carries responses in RPC mode
+    output::set_quiet(args.quiet || matches!(cmd, Commands::Rpc));
+    output::set_responses_only(matches!(cmd, Commands::Rpc));
+
+    // Resolve the setting before looking for the device so typos are reported right away
+    let feature = match &cmd {
+        Commands::FeatureSet { name: None, .. } | Commands::Encode { name: None, .. } => {
+            print_settings();
+            return Ok(());
+        },
+        Commands::FeatureSet { name: Some(name), value: Some(value), profile }
+        | Commands::Encode { name: Some(name), value: Some(value), profile } => {
+            Some(resolve_setting(name, value, *profile, args.clamp))
+        },
+        _ => None,
+    };
+    let settings = match &cmd {
+        Commands::Set { values, profile } => resolve_set(values, *profile, args.clamp),
+        _ => Vec::new(),
+    };
+    let replay = match &cmd {
+        Commands::Replay { path, .. } => std::fs::read(path).unwrap_or_else(|e| {
+            error!("Failed to read {}: {}", path.display(), e);
+            std::process::exit(1);
+        }),
+        _ => Vec::new(),
+    };
+
+    // Encoding is done offline, with the limits of the model given by --pid if it is known
+    if let Commands::Encode { .. } = cmd {
+        let (setting, profile, value) = feature.expect("resolved above");
+        let model = KNOWN_MODELS.iter().find(|m| m.vid == args.vid && Some(m.pid) == args.pid);
+        let format = ReportFormat {
+            len: args.report_len.unwrap_or(model.map_or(REPORT_LEN, |m| m.report_len)),
+            checksum: args.payload_checksum,
+            report_id: args.report_id,
+        };
+
+        validate((setting.check)(profile, value, model.map_or(MAX_PROFILES, |m| m.max_profiles)), args.no_validate);
+        print_report(&format.build((setting.encode)(profile, value)));
+        return Ok(());
+    }
+
+    // Enumeration may be restricted when a device node is passed in, libusb must not attempt it
+    #[cfg(unix)]
+    if args.path.is_some()
+        && let Err(e) = rusb::disable_device_discovery()
+    {
+        warning!("Warning: Failed to disable device discovery: {}", e);
+    }
+
+    // Only commands that write can fall back to printing their reports
+    let absent_ok = args.allow_absent && cmd.writes();
+
+    let mut context = match Context::new() {
+        Ok(context) => Some(context),
+        Err(e) if absent_ok => {
+            warning!("Warning: Failed to initialize libusb: {}", e);
+            None
+        },
+        Err(e) => {
+            error!("Failed to initialize libusb: {}", e);
+            if !cfg!(feature = "vendored") {
+                error!("If libusb is missing on this system, rebuild with `cargo build --release --features vendored`");
+            }
+            std::process::exit(1);
+        }
+    };
+
+    let opened = match (&mut context, &args.path) {
+        (None, _) => Err(Error::NotFound),
+        (Some(context), Some(path)) => open_path(context, path),
+        (Some(context), None) => {
+            let mut candidates = find_devices(context, args.vid, &pids)?;
+
+            if let Commands::List = cmd {
+                print_devices(&candidates, args.format);
+                return Ok(());
+            }
+
+            if let Some(serial) = &args.serial {
+                candidates.retain(|(device, _)| read_serial(device).as_ref() == Some(serial));
+            }
 
-    let mut context = Context::new()?;
-    let (device, mut handle) = match open_device(&mut context, VID, PID) {
-        Ok(e) => e,
+            // An explicit selection wins over the remembered device
+            if args.serial.is_none()
+                && args.device_index.is_none()
+                && !args.forget
+                && let Some(serial) = remembered_serial()
+            {
+                let remembered: Vec<_> = candidates
+                    .iter()
+                    .filter(|(device, _)| read_serial(device).as_ref() == Some(&serial))
+                    .cloned()
+                    .collect();
+                if remembered.is_empty() {
+                    status!("Remembered device {} not found, selecting the first match", serial);
+                } else {
+                    candidates = remembered;
+                }
+            }
+
+            if let Some(index) = args.device_index {
+                if index >= candidates.len() {
+                    error!("Device index {} is out of range, {} matching device(s) found", index, candidates.len());
+                    std::process::exit(1);
+                }
+                candidates = vec![candidates.swap_remove(index)];
+            }
+
+            open_device(candidates, args.wait)
+        },
+    };
+
+    let opened = match opened {
+        Ok(e) => Some(e),
+        Err(Error::NotFound) if absent_ok => {
+            warning!("Warning: no device found, printing the reports without sending them");
+            None
+        },
         Err(Error::NotFound) => {
-            eprintln!("Device not found");
+            match &args.path {
+                Some(path) => error!("Device not found at {}", path.display()),
+                None => error!("Device not found"),
+            }
+            std::process::exit(1);
+        },
+        Err(Error::Busy) => {
+            error!("Device is busy, another program is probably holding it (use --wait to retry)");
+            std::process::exit(1);
+        },
+        Err(Error::NotSupported) if args.path.is_some() => {
+            error!("Opening a device node by path is not supported on this platform");
             std::process::exit(1);
         },
-        Err(_) => {
-            eprintln!("Failed to open USB device");
+        Err(Error::Access) => {
+            error!("Permission denied opening the device, run as root or install a udev rule granting access");
+            std::process::exit(1);
+        },
+        Err(e) => {
+            match &args.path {
+                Some(path) => error!("Failed to open {}: {}", path.display(), e),
+                None => error!("Failed to open USB device: {} (the device was found, retrying may help)", e),
+            }
             std::process::exit(1);
         }
     };
 
-    println!(
-        "Mouse found on bus {:03} with device id {:03}",
-        device.bus_number(),
-        device.address()
-    );
+    let (vid, pid) = match &opened {
+        Some((device, handle, pid)) => {
+            success!(
+                "Mouse found on bus {:03} with device id {:03} (PID {:#06x})",
+                device.bus_number(),
+                device.address(),
+                pid
+            );
+            if args.remember {
+                remember(device, handle);
+            }
+            // Devices opened by path may not match --vid
+            (device.device_descriptor().map_or(args.vid, |d| d.vendor_id()), *pid)
+        },
+        None => (args.vid, pids.first().copied().unwrap_or(PID)),
+    };
+    let model = KNOWN_MODELS.iter().find(|m| m.vid == vid && m.pid == pid);
+    if let Some(model) = model {
+        if opened.is_some() {
+            status!("Detected model: {} ({} profiles)", model.name, model.max_profiles);
+        } else {
+            status!("Building the reports for the {} ({} profiles)", model.name, model.max_profiles);
+        }
+        if let Some((device, ..)) = &opened
+            && args.warn_on_rebadge
+        {
+            check_rebadge(device, model);
+        }
+    }
+    let format = ReportFormat {
+        len: args.report_len.unwrap_or(model.map_or(REPORT_LEN, |m| m.report_len)),
+        checksum: args.payload_checksum,
+        report_id: args.report_id,
+    };
+    let max_profiles = model.map_or(MAX_PROFILES, |m| m.max_profiles);
 
-    println!("Claiming interfaces...");
-    // Detach from interfaces
-    handle.detach_kernel_driver(0)?;
-    handle.detach_kernel_driver(1)?;
-    // Claim interfaces
-    handle.claim_interface(0)?;
-    handle.claim_interface(1)?;
+    // The report length is only known once the model is, but must be checked before claiming
+    if let Commands::Replay { path, .. } = &cmd
+        && (replay.is_empty() || replay.len() % format.len != 0)
+    {
+        error!(
+            "{} holds {} byte(s), expected a multiple of the {} byte report length",
+            path.display(),
+            replay.len(),
+            format.len
+        );
+        std::process::exit(1);
+    }
 
-    match args.cmd {
-        Commands::Activate { count } => {
-            if !(1..4).contains(&count) {
-                eprintln!("Count must be in range [1;4]");
-                std::process::exit(1);
+    // Our reports could do anything to an unrelated device picked up through a wrong --pid
+    if model.is_none() && cmd.writes() && !args.dry_run && !args.force {
+        error!(
+            "{:04x}:{:04x} is not a known model, refusing to write to it (use --force to override)",
+            vid,
+            pid
+        );
+        std::process::exit(1);
+    }
+
+    if args.no_validate {
+        warning!("WARNING: range validation is disabled, out-of-range values will be sent to the mouse as is");
+    }
+    if let Some(profile) = args.after_select {
+        validate(protocol::check_profile(profile, max_profiles), args.no_validate);
+    }
+    // A failed check exits, which skips releasing the interfaces, so every check runs before claiming them
+    match &cmd {
+        Commands::Activate { count } => validate(protocol::check_profiles_count(*count, max_profiles), args.no_validate),
+        Commands::Select { profile } => validate(protocol::check_profile(*profile, max_profiles), args.no_validate),
+        // Every setting of set is checked before any is sent
+        Commands::Set { .. } => {
+            for (setting, profile, value) in &settings {
+                validate((setting.check)(*profile, *value, max_profiles), args.no_validate);
             }
+        },
+        Commands::FeatureSet { .. } => {
+            let (setting, profile, value) = feature.expect("resolved before opening the device");
+            validate((setting.check)(profile, value, max_profiles), args.no_validate);
+        },
+        _ => {}
+    }
+
+    let available = match &opened {
+        Some((device, ..)) if cmd.writes() => check_interface(device, args.interface),
+        _ => None,
+    };
 
-            set_profiles_count(&mut handle, count)?;
+    // Read-only commands don't need the interfaces
+    match cmd {
+        Commands::Info => {
+            let (device, handle, _) = opened.as_ref().expect("only writing commands run without a device");
+            print_info(device, handle);
+            return Ok(());
+        },
+        Commands::Capabilities => {
+            print_capabilities(model);
+            return Ok(());
+        },
+        // Enumeration lists every match before opening, only a device given by --path gets here
+        Commands::List => {
+            let (device, _, pid) = opened.as_ref().expect("only writing commands run without a device");
+            print_devices(&[(device.clone(), *pid)], args.format);
+            return Ok(());
+        },
+        _ => {}
+    }
+
+    let mut mouse = Mouse {
+        handle: opened.map(|(_, handle, _)| usb::Claimed::new(handle)),
+        format,
+        delay: Duration::from_millis(args.delay_ms),
+        timeout_write: Duration::from_millis(args.timeout_write),
+        timeout_read: Duration::from_millis(args.timeout_read),
+        dry_run: args.dry_run,
+        verbose: args.verbose,
+        interface: args.interface,
+        max_profiles,
+        no_validate: args.no_validate,
+        clamp: args.clamp,
+        stats: Stats::default(),
+        sent: Vec::new(),
+        report_raw_on_error: args.report_raw_on_error,
+        confirm_writes: args.confirm_writes,
+    };
+
+    // Nothing is sent on a dry run so the interfaces are left alone
+    if !args.dry_run {
+        status!("Claiming interfaces...");
+        mouse.handle().set_reattach(!args.no_reattach);
+        // Layouts other than INTERFACES only get the ones they have, the report interface included
+        let mut interfaces: Vec<u8> = INTERFACES
+            .iter()
+            .copied()
+            .filter(|iface| available.as_ref().is_none_or(|available| available.contains(iface)))
+            .collect();
+        if !interfaces.contains(&args.interface) {
+            interfaces.push(args.interface);
+        }
+        mouse.handle().claim(&interfaces, !args.no_detach)?;
+    }
+
+    // Describes what was applied to the post command hook
+    let mut hook_env: Vec<(&str, String)> = Vec::new();
+
+    let result = match cmd {
+        Commands::Activate { count } => {
+            hook_env.push(("RUSTAR_COMMAND", "activate".to_string()));
+            hook_env.push(("RUSTAR_COUNT", count.to_string()));
+
+            set_profiles_count(&mut mouse, count)
         },
         Commands::Select { profile } => {
-            if profile > 3 {
-                eprintln!("Profile must be in range [0;3]");
-                std::process::exit(1);
-            }
+            hook_env.push(("RUSTAR_COMMAND", "select".to_string()));
+            hook_env.push(("RUSTAR_PROFILE", profile.to_string()));
 
-            switch_profile(&mut handle, profile)?;
+            switch_profile(&mut mouse, profile)
         },
-        Commands::Set { profile, value } => {
-            if profile > 3 {
-                eprintln!("Profile must be in range [0;3]");
-                std::process::exit(1);
+        Commands::Set { .. } => {
+            hook_env.push(("RUSTAR_COMMAND", "set".to_string()));
+            if let Some((_, profile, _)) = settings.iter().find(|(setting, ..)| setting.per_profile) {
+                hook_env.push(("RUSTAR_PROFILE", profile.to_string()));
             }
-            if !(50..26000).contains(&value) {
-                eprintln!("DPI value must be in range [50;26000] it will be rounded down to a multiple of 50");
-                std::process::exit(1);
+            for (setting, _, value) in &settings {
+                hook_env.push((hook_var(setting), value.to_string()));
             }
 
-            set_profile_dpi(&mut handle, profile, value)?;
-        }
+            apply_settings(&mut mouse, &settings)
+        },
+        Commands::FeatureSet { .. } => {
+            let (setting, profile, value) = feature.expect("resolved before opening the device");
+            hook_env.push(("RUSTAR_COMMAND", "feature-set".to_string()));
+            hook_env.push(("RUSTAR_SETTING", setting.name.to_string()));
+            hook_env.push(("RUSTAR_VALUE", value.to_string()));
+            if setting.per_profile {
+                hook_env.push(("RUSTAR_PROFILE", profile.to_string()));
+            }
+
+            write_set_report(&mut mouse, (setting.encode)(profile, value))
+        },
+        Commands::Rpc => {
+            hook_env.push(("RUSTAR_COMMAND", "rpc".to_string()));
+            rpc::serve(&mut mouse).map(|_| 0)
+        },
+        Commands::Replay { request_type, value, index, .. } => {
+            let len = mouse.format.len;
+            let value = value.unwrap_or(REPORT_TYPE | args.report_id as u16);
+            let index = index.unwrap_or(args.interface as u16);
+            hook_env.push(("RUSTAR_COMMAND", "replay".to_string()));
+
+            replay
+                .chunks(len)
+                .try_fold(0, |written, report| Ok(written + write_report(&mut mouse, request_type, value, index, report)?))
+        },
+        Commands::Serve { addr } => {
+            hook_env.push(("RUSTAR_COMMAND", "serve".to_string()));
+            http::serve(&mut mouse, addr).map(|_| 0)
+        },
+        Commands::Info | Commands::List | Commands::Capabilities | Commands::Encode { .. } => {
+            unreachable!("handled before claiming interfaces")
+        },
+    };
+    // Only chain the switch when the command itself went through
+    let result = result.and_then(|written| match args.after_select {
+        Some(profile) => {
+            status!("Switching to profile {}...", profile);
+            hook_env.push(("RUSTAR_ACTIVE_PROFILE", profile.to_string()));
+            switch_profile(&mut mouse, profile)
+        },
+        None => Ok(written),
+    });
+
+    match result {
+        Ok(_) => success!("Done"),
+        Err(Error::NoDevice) => {
+            error!("Device disconnected during operation");
+            std::process::exit(EXIT_DISCONNECTED);
+        },
+        Err(e) => return Err(e),
+    }
+
+    // Nothing is sent on a dry run so there is nothing to dump
+    if let Some(path) = &args.dump_last
+        && !args.dry_run
+        && let Err(e) = std::fs::write(path, &mouse.sent)
+    {
+        warning!("Warning: failed to write the sent reports to {}: {}", path.display(), e);
     }
 
     // cleanup after use
-    println!("Releasing interfaces...");
-    // Only release the interfaces we claimed
-    handle.release_interface(0)?;
-    handle.release_interface(1)?;
-    // Reattach borrowed interfaces
-    handle.attach_kernel_driver(0)?;
-    handle.attach_kernel_driver(1)?;
+    if args.no_cleanup && !args.dry_run {
+        mouse.handle().leave();
+        warning!("Warning: --no-cleanup left the interfaces claimed and the kernel driver detached, the mouse may not respond until replugged");
+    } else if !args.dry_run {
+        status!("Releasing interfaces...");
+        // Only re-attach the kernel driver where we detached it
+        let detached = !mouse.handle().detached().is_empty();
+        mouse.handle().release()?;
+        if args.no_reattach && detached {
+            warning!("Warning: kernel driver not re-attached, the mouse may not respond until replugged or until rustar runs without --no-reattach");
+        }
+    }
+
+    // Nothing was applied on a dry run
+    if let Some(hook) = &args.post_command_hook
+        && !args.dry_run
+    {
+        run_hook(hook, &hook_env);
+    }
+
+    if args.stats {
+        data!(
+            "{} transfer(s), {} byte(s) sent in {}ms",
+            mouse.stats.transfers,
+            mouse.stats.bytes,
+            start.elapsed().as_millis()
+        );
+    }
 
     Ok(())
 }
 
-fn open_device<T: UsbContext>(
-    context: &mut T,
-    vid: u16,
-    pid: u16,
-) -> Result<(Device<T>, DeviceHandle<T>)> {
-    let devices = context.devices()?;
+// Exits when the interface isn't one of the device's, listing the available ones. Returns the
+// device's interfaces, None when they can't be read
+fn check_interface<T: UsbContext>(device: &Device<T>, interface: u8) -> Option<Vec<u8>> {
+    // Some stacks report no configuration until the device settles, nothing can be claimed then
+    if device.device_descriptor().is_ok_and(|desc| desc.num_configurations() == 0) {
+        no_configuration();
+    }
+    let available: Vec<u8> = match device.active_config_descriptor() {
+        Ok(config) => config.interfaces().map(|i| i.number()).collect(),
+        Err(e) => {
+            warning!("Warning: Failed to read the interfaces, can't check interface {}: {}", interface, e);
+            return None;
+        },
+    };
 
-    for device in devices.iter() {
-        let device_desc = match device.device_descriptor() {
-            Ok(d) => d,
-            Err(e) => {
-                eprintln!("Warning: Failed to get device descriptor: {}", e);
-                continue;                
-            },
-        };
+    if available.is_empty() {
+        no_configuration();
+    }
+    if !available.contains(&interface) {
+        let available = available.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+        error!("Interface {} doesn't exist, available interfaces: {}", interface, available);
+        std::process::exit(1);
+    }
+
+    Some(available)
+}
+
+// Warns when the interfaces differ from the INTERFACES layout every known model shares
+fn check_rebadge<T: UsbContext>(device: &Device<T>, model: &Model) {
+    let Ok(config) = device.active_config_descriptor() else {
+        return;
+    };
+    let interfaces: Vec<u8> = config.interfaces().map(|i| i.number()).collect();
+
+    if interfaces != INTERFACES {
+        let interfaces = interfaces.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+        warning!(
+            "Warning: this device has interfaces [{}] unlike the {}, it may be a rebadge and behave differently. \
+             Please report its layout (rustar info)",
+            interfaces,
+            model.name
+        );
+    }
+}
+
+fn no_configuration() -> ! {
+    error!("The device reports no configuration or interface, replug it or check its permissions");
+    std::process::exit(1);
+}
+
+// Runs the post command hook through the shell, a failing hook only warns
+fn run_hook(hook: &str, env: &[(&str, String)]) {
+    let mut command = if cfg!(windows) {
+        let mut command = std::process::Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = std::process::Command::new("sh");
+        command.arg("-c");
+        command
+    };
+
+    match command.arg(hook).envs(env.iter().map(|(key, value)| (key, value))).status() {
+        Ok(status) if status.success() => {},
+        Ok(status) => warning!("Warning: post command hook failed ({})", status),
+        Err(e) => warning!("Warning: failed to run the post command hook: {}", e),
+    }
+}
+
+// Exits on a failed range check, unless validation was disabled in which case it only warns
+fn validate(check: std::result::Result<(), String>, no_validate: bool) {
+    if let Err(e) = check {
+        if no_validate {
+            warning!("Ignored: {}", e);
+        } else {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Names of the settings, so unknown ones are rejected while parsing with suggestions
+fn setting_names() -> PossibleValuesParser {
+    PossibleValuesParser::new(protocol::SETTINGS.iter().map(|setting| setting.name))
+}
+
+// Looks a setting up and parses its value, exiting with a message when either is invalid
+fn resolve_setting(name: &str, value: &str, profile: Option<u8>, clamp: bool) -> (&'static protocol::Setting, u8, u16) {
+    let Some(setting) = protocol::find_setting(name) else {
+        error!("Unknown setting '{}'", name);
+        print_settings();
+        std::process::exit(1);
+    };
+    let value = (setting.parse)(value).unwrap_or_else(|e| {
+        error!("{}", e);
+        std::process::exit(1);
+    });
+    // Clamping happens first, the clamped value is then rounded down like any other
+    let value = if setting.name == "dpi" { clamp_dpi(Dpi(value), clamp).0 } else { value };
+    let profile = match (setting.per_profile, profile) {
+        (true, Some(profile)) => profile,
+        (true, None) => {
+            error!("Setting '{}' applies to a single profile, pass it with --profile", setting.name);
+            std::process::exit(1);
+        },
+        (false, _) => 0,
+    };
+
+    (setting, profile, value)
+}
+
+// Brings an out-of-range DPI within limits when --clamp is set, warning about it
+pub(crate) fn clamp_dpi(dpi: Dpi, clamp: bool) -> Dpi {
+    if clamp && dpi.clamp() != dpi {
+        // Oversized inputs saturate while parsing, so only the range is worth showing
+        warning!("Warning: DPI out of range [{};{}], clamped to {}", MIN_DPI, MAX_DPI, dpi.clamp().0);
+        return dpi.clamp();
+    }
+
+    dpi
+}
+
+// Resolves the arguments of set, either a profile and a DPI value or key=value pairs
+fn resolve_set(values: &[String], profile: Option<u8>, clamp: bool) -> Vec<(&'static protocol::Setting, u8, u16)> {
+    if let [first, dpi] = values
+        && !first.contains('=')
+        && !dpi.contains('=')
+    {
+        if profile.is_some() {
+            error!("--profile only applies to key=value settings, the profile is already given as '{}'", first);
+            std::process::exit(1);
+        }
+        let profile = first.parse::<u8>().unwrap_or_else(|e| {
+            error!("invalid profile '{}': {}", first, e);
+            std::process::exit(1);
+        });
+        return vec![resolve_setting("dpi", dpi, Some(profile), clamp)];
+    }
+
+    values
+        .iter()
+        .map(|pair| {
+            let Some((name, value)) = pair.split_once('=') else {
+                error!("Expected key=value, got '{}'", pair);
+                std::process::exit(1);
+            };
+            resolve_setting(name, value, profile, clamp)
+        })
+        .collect()
+}
+
+// Environment variable a setting is exported as to the post command hook, named like the
+// single-setting commands do
+fn hook_var(setting: &protocol::Setting) -> &'static str {
+    match setting.name {
+        "dpi" => "RUSTAR_DPI",
+        "profile" => "RUSTAR_ACTIVE_PROFILE",
+        "profiles" => "RUSTAR_COUNT",
+        _ => "RUSTAR_VALUE",
+    }
+}
+
+// Sends the settings in order, stopping at the first failure, and prints what became of each
+// one when there are several
+fn apply_settings<T: UsbContext>(mouse: &mut Mouse<T>, settings: &[(&protocol::Setting, u8, u16)]) -> Result<usize> {
+    let mut results = Vec::new();
+    for &(setting, profile, value) in settings {
+        let result = write_set_report(mouse, (setting.encode)(profile, value));
+        let failed = result.is_err();
+        results.push(result);
+        if failed {
+            break;
+        }
+    }
+
+    if settings.len() > 1 {
+        for (i, (setting, profile, value)) in settings.iter().enumerate() {
+            let target = if setting.per_profile { format!(" (profile {})", profile) } else { String::new() };
+            let outcome = match results.get(i) {
+                Some(Ok(_)) => "applied".to_string(),
+                Some(Err(e)) => format!("failed: {}", e),
+                None => "not sent".to_string(),
+            };
+            status!("  {} = {}{}: {}", setting.name, value, target, outcome);
+        }
+    }
+
+    results.pop().unwrap_or(Ok(0))
+}
+
+// Prints the settings feature-set and encode accept
+fn print_settings() {
+    println!("Available settings:");
+    for setting in protocol::SETTINGS {
+        let scope = if setting.per_profile { " (per profile)" } else { "" };
+        println!("  {:<10} {}{}", setting.name, setting.description, scope);
+    }
+}
+
+// Prints the matching devices in selection order, the index being the one --device-index expects
+fn print_devices<T: UsbContext>(devices: &[(Device<T>, u16)], format: table::Format) {
+    if devices.is_empty() {
+        status!("No matching device found");
+        return;
+    }
+
+    let rows: Vec<Vec<String>> = devices
+        .iter()
+        .enumerate()
+        .map(|(index, (device, pid))| {
+            let strings = device.open().map(|handle| read_strings(device, &handle)).unwrap_or_default();
+
+            vec![
+                index.to_string(),
+                format!("{:03}", device.bus_number()),
+                format!("{:03}", device.address()),
+                format!("{:#06x}", pid),
+                strings.manufacturer.unwrap_or_default(),
+                strings.product.unwrap_or_default(),
+                strings.serial.unwrap_or_default(),
+            ]
+        })
+        .collect();
+
+    table::print(format, &["INDEX", "BUS", "DEVICE", "PID", "MANUFACTURER", "PRODUCT", "SERIAL"], &rows);
+}
+
+// String descriptors of a device, None for the ones it doesn't have
+#[derive(Default)]
+struct DeviceStrings {
+    manufacturer: Option<String>,
+    product: Option<String>,
+    serial: Option<String>,
+}
+
+fn read_strings<T: UsbContext>(device: &Device<T>, handle: &DeviceHandle<T>) -> DeviceStrings {
+    let Ok(desc) = device.device_descriptor() else {
+        return DeviceStrings::default();
+    };
+
+    DeviceStrings {
+        manufacturer: desc.manufacturer_string_index().and_then(|_| handle.read_manufacturer_string_ascii(&desc).ok()),
+        product: desc.product_string_index().and_then(|_| handle.read_product_string_ascii(&desc).ok()),
+        serial: desc.serial_number_string_index().and_then(|_| handle.read_serial_number_string_ascii(&desc).ok()),
+    }
+}
+
+// Reads the serial number string, None when the device has none or can't be opened
+fn read_serial<T: UsbContext>(device: &Device<T>) -> Option<String> {
+    read_strings(device, &device.open().ok()?).serial
+}
+
+// File holding the serial number saved by --remember, under the XDG state directory
+fn remembered_path() -> Option<PathBuf> {
+    let state = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => Path::new(&std::env::var_os("HOME")?).join(".local/state"),
+    };
+
+    Some(state.join("rustar").join("device"))
+}
+
+fn remembered_serial() -> Option<String> {
+    let serial = std::fs::read_to_string(remembered_path()?).ok()?;
+    let serial = serial.trim();
 
-        if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
-            match device.open() {
-                Ok(handle) => return Ok((device, handle)),
+    (!serial.is_empty()).then(|| serial.to_string())
+}
+
+// Saves the serial number of the opened device, only warning on failure since the command can still run
+fn remember<T: UsbContext>(device: &Device<T>, handle: &DeviceHandle<T>) {
+    let Some(serial) = read_strings(device, handle).serial else {
+        warning!("Warning: the device has no serial number, it can't be remembered");
+        return;
+    };
+    let Some(path) = remembered_path() else {
+        warning!("Warning: neither XDG_STATE_HOME nor HOME is set, the device can't be remembered");
+        return;
+    };
+
+    let saved = path
+        .parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, format!("{}\n", serial)));
+    match saved {
+        Ok(()) => status!("Remembered device {}", serial),
+        Err(e) => warning!("Warning: failed to write {}: {}", path.display(), e),
+    }
+}
+
+// Prints what a model supports, anything not described by KNOWN_MODELS is reported as unknown
+fn print_capabilities(model: Option<&Model>) {
+    const UNKNOWN: &str = "unknown";
+
+    let name = model.map_or(UNKNOWN.to_string(), |m| m.name.to_string());
+    let profiles = model.map_or(UNKNOWN.to_string(), |m| m.max_profiles.to_string());
+    let dpi = model.map_or(UNKNOWN.to_string(), |_| format!("[{};{}] in steps of 50", MIN_DPI, MAX_DPI));
+    let report_len = model.map_or(UNKNOWN.to_string(), |m| m.report_len.to_string());
+
+    println!("Model:         {}", name);
+    println!("Profiles:      {}", profiles);
+    println!("DPI range:     {}", dpi);
+    println!("Report length: {}", report_len);
+    // None of these have been reverse-engineered yet for any model
+    println!("Polling rates: {}", UNKNOWN);
+    println!("LED:           {}", UNKNOWN);
+    println!("Battery:       {}", UNKNOWN);
+    println!("Lift-off:      {}", UNKNOWN);
+}
+
+// Prints the bus topology of the matched device and its interface layout
+fn print_info<T: UsbContext>(device: &Device<T>, handle: &DeviceHandle<T>) {
+    let port_path = match device.port_numbers() {
+        Ok(ports) => ports.iter().map(|p| p.to_string()).collect::<Vec<_>>().join("."),
+        Err(e) => format!("unknown ({})", e),
+    };
+    let speed = match device.speed() {
+        Speed::Low => "low (1.5 Mbps)",
+        Speed::Full => "full (12 Mbps)",
+        Speed::High => "high (480 Mbps)",
+        Speed::Super => "super (5 Gbps)",
+        Speed::SuperPlus => "super+ (10 Gbps)",
+        _ => "unknown",
+    };
+
+    let interfaces = match device.active_config_descriptor() {
+        Ok(config) => config.interfaces().map(|i| i.number().to_string()).collect::<Vec<_>>().join(", "),
+        Err(e) => format!("unknown ({})", e),
+    };
+
+    let strings = read_strings(device, handle);
+
+    println!("Manufacturer: {}", strings.manufacturer.as_deref().unwrap_or("unknown"));
+    println!("Product:      {}", strings.product.as_deref().unwrap_or("unknown"));
+    println!("Serial:       {}", strings.serial.as_deref().unwrap_or("none"));
+    println!("Bus:          {:03}", device.bus_number());
+    println!("Port:         {}", port_path);
+    println!("Address:      {:03}", device.address());
+    println!("Speed:        {}", speed);
+    println!("Interfaces:   {}", interfaces);
+    match device.get_parent() {
+        Some(parent) => println!("Parent:       bus {:03} device {:03}", parent.bus_number(), parent.address()),
+        None => println!("Parent:       none (root hub)"),
+    }
+}
+
+// Prints every effective setting along with the layer it was resolved from
+fn show_config(args: &Args, matches: &ArgMatches, pids: &[u16]) {
+    let source = |id: &str| match matches.value_source(id) {
+        Some(ValueSource::CommandLine) => "command line",
+        Some(ValueSource::EnvVariable) => "environment",
+        _ => "default",
+    };
+
+    let pids_source = if args.pid.is_some() {
+        source("pid")
+    } else if !args.pid_list.is_empty() {
+        source("pid_list")
+    } else {
+        "known models"
+    };
+    let pids = pids.iter().map(|pid| format!("{:#06x}", pid)).collect::<Vec<_>>().join(", ");
+    let interfaces = INTERFACES.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ");
+
+    println!("vid        = {:#06x} ({})", args.vid, source("vid"));
+    println!("pids       = [{}] ({})", pids, pids_source);
+    if let Some(path) = &args.path {
+        println!("path       = {} ({})", path.display(), source("path"));
+    }
+    if let Some(serial) = &args.serial {
+        println!("serial     = {} ({})", serial, source("serial"));
+    }
+    match args.device_index {
+        Some(index) => println!("device     = index {} ({})", index, source("device_index")),
+        None => println!("device     = first match (default)"),
+    }
+    println!("wait       = {} ({})", args.wait, source("wait"));
+    println!(
+        "timeout    = write {}ms ({}), read {}ms ({})",
+        args.timeout_write,
+        source("timeout_write"),
+        args.timeout_read,
+        source("timeout_read")
+    );
+    println!("delay      = {}ms ({})", args.delay_ms, source("delay_ms"));
+    println!("report_id  = {:#04x} ({})", args.report_id, source("report_id"));
+    match args.report_len {
+        Some(len) => println!("report_len = {} ({})", len, source("report_len")),
+        None => println!("report_len = detected model's (default)"),
+    }
+    if let Some(checksum) = args.payload_checksum.to_possible_value() {
+        println!("checksum   = {} ({})", checksum.get_name(), source("payload_checksum"));
+    }
+    println!("detach     = {} ({})", !args.no_detach, source("no_detach"));
+    println!("reattach   = {} ({})", !args.no_reattach && !args.no_detach, source("no_reattach"));
+    println!("interfaces = [{}] (built-in)", interfaces);
+    println!("interface  = {} ({})", args.interface, source("interface"));
+}
+
+fn parse_report_len(s: &str) -> std::result::Result<usize, String> {
+    let len = s.parse::<usize>().map_err(|e| format!("invalid length '{}': {}", s, e))?;
+    protocol::check_report_len(len)?;
+
+    Ok(len)
+}
+
+// Parses a u16 given either in decimal or in hexadecimal with a 0x prefix
+fn parse_u16(s: &str) -> std::result::Result<u16, String> {
+    let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    parsed.map_err(|e| format!("invalid value '{}': {}", s, e))
+}
+
+// Parses a u8 given either in decimal or in hexadecimal with a 0x prefix
+fn parse_u8(s: &str) -> std::result::Result<u8, String> {
+    let value = parse_u16(s)?;
+
+    u8::try_from(value).map_err(|_| format!("invalid value '{}': must fit in a byte", s))
+}
+
+// Lists the devices matching the VID and one of the PIDs, ordered by PID then enumeration order
+fn find_devices<T: UsbContext>(context: &mut T, vid: u16, pids: &[u16]) -> Result<Vec<(Device<T>, u16)>> {
+    let devices = context.devices()?;
+    let mut matches = Vec::new();
+
+    for &pid in pids {
+        for device in devices.iter() {
+            let device_desc = match device.device_descriptor() {
+                Ok(d) => d,
                 Err(e) => {
-                    eprintln!("Failed to open the device: {}", e);
+                    warning!("Warning: Failed to get device descriptor: {}", e);
                     continue;
+                },
+            };
+
+            if device_desc.vendor_id() == vid && device_desc.product_id() == pid {
+                matches.push((device, pid));
+            }
+        }
+    }
+
+    Ok(matches)
+}
+
+// Opens a device node through its file descriptor, bypassing enumeration
+#[cfg(unix)]
+fn open_path<T: UsbContext>(context: &T, path: &Path) -> Result<(Device<T>, DeviceHandle<T>, u16)> {
+    use std::os::fd::IntoRawFd;
+
+    let file = std::fs::OpenOptions::new().read(true).write(true).open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => Error::NotFound,
+        std::io::ErrorKind::PermissionDenied => Error::Access,
+        _ => Error::Io,
+    })?;
+    // libusb only borrows the descriptor, so it is kept open for the rest of the process
+    let fd = file.into_raw_fd();
+    // SAFETY: fd is a valid, open usbfs descriptor that is never closed while the handle lives
+    let handle = unsafe { context.open_device_with_fd(fd)? };
+    let device = handle.device();
+    let pid = device.device_descriptor()?.product_id();
+
+    Ok((device, handle, pid))
+}
+
+#[cfg(not(unix))]
+fn open_path<T: UsbContext>(_context: &T, _path: &Path) -> Result<(Device<T>, DeviceHandle<T>, u16)> {
+    Err(Error::NotSupported)
+}
+
+// Returns the first candidate that could be opened along with its PID
+fn open_device<T: UsbContext>(
+    candidates: Vec<(Device<T>, u16)>,
+    wait: bool,
+) -> Result<(Device<T>, DeviceHandle<T>, u16)> {
+    let mut busy = false;
+    let mut last_error = None;
+
+    for (device, pid) in candidates {
+        match open_with_wait(&device, wait) {
+            Ok(handle) => return Ok((device, handle, pid)),
+            Err(Error::Busy) => {
+                busy = true;
+                continue;
+            }
+            Err(e) => {
+                warning!("Warning: failed to open the device on bus {:03} with device id {:03}: {}", device.bus_number(), device.address(), e);
+                last_error = Some(e);
+                continue;
+            }
+        }
+    }
+
+    // NotFound only when nothing matched, a matching device that couldn't be opened reports why
+    Err(if busy { Error::Busy } else { last_error.unwrap_or(Error::NotFound) })
+}
+
+// Opens the device, polling for up to BUSY_WAIT while it is busy when wait is set
+fn open_with_wait<T: UsbContext>(device: &Device<T>, wait: bool) -> Result<DeviceHandle<T>> {
+    const SPINNER: [char; 4] = ['|', '/', '-', '\\'];
+
+    let start = Instant::now();
+    // The spinner redraws a line, which JSON logs can't hold
+    let tty = std::io::stderr().is_terminal() && !output::is_json();
+    let mut frame = 0;
+
+    let result = loop {
+        match device.open() {
+            Err(Error::Busy) if wait && start.elapsed() < BUSY_WAIT => {
+                if tty {
+                    eprint!("\r{} Device busy, waiting...", SPINNER[frame % SPINNER.len()]);
+                    let _ = std::io::stderr().flush();
+                } else if frame == 0 {
+                    warning!("Device busy, waiting...");
                 }
+                frame += 1;
+                std::thread::sleep(Duration::from_millis(100));
             }
+            result => break result,
         }
+    };
+
+    if tty && frame > 0 {
+        eprintln!();
     }
 
-    Err(Error::NotFound)
+    result
 }
 
 // profile must be in range [0;3] TODO get how many profiles are active from the mouse
-fn switch_profile<T: UsbContext>(handle: &mut DeviceHandle<T>, profile: u8) -> Result<usize> {
-    let data: [u8; 17] = [
-        0x08, 0x07, 0x00, 0x00, 0x04, 0x02, profile, 0x55 - profile, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0xeb,
-    ];
+fn switch_profile<T: UsbContext>(mouse: &mut Mouse<T>, profile: u8) -> Result<usize> {
+    let data = protocol::encode_switch_profile(profile);
 
-    write_set_report(handle, data)
+    write_set_report(mouse, data)
 }
 
+// count must be in range [1;max_profiles] for the model
+// count is checked against the model's max_profiles by the callers, before the interfaces are claimed
+fn set_profiles_count<T: UsbContext>(mouse: &mut Mouse<T>, count: u8) -> Result<usize> {
+    let data = protocol::encode_profiles_count(count);
 
-// count must be in range [1;4]
-fn set_profiles_count<T: UsbContext>(handle: &mut DeviceHandle<T>, count: u8) -> Result<usize> {
-    let data: [u8; 17] = [
-        0x08, 0x07, 0x00, 0x00, 0x02, 0x02, count, 0x55 - count, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, 0xed,
-    ];
-
-    write_set_report(handle, data)
+    write_set_report(mouse, data)
 }
 
 // profile must be in range [0;3]
-fn set_profile_dpi<T: UsbContext>(handle: &mut DeviceHandle<T>, profile: u8, dpi: u16) -> Result<usize> {
-    let dpi_index: u16 = (dpi / 50) - 1;
-    let lo: u8 = dpi_index as u8 ;
-    let hi: u8 = (dpi_index >> 8) as u8;
-    let checksum = 0x155 - (0x13 + (0x0c + profile as u16 * 4) + 0x55);
+fn set_profile_dpi<T: UsbContext>(mouse: &mut Mouse<T>, profile: u8, dpi: u16) -> Result<usize> {
+    let data = protocol::encode_set_dpi(profile, dpi);
+
+    write_set_report(mouse, data)
+}
+
+fn write_set_report<T: UsbContext>(mouse: &mut Mouse<T>, data: [u8; REPORT_LEN]) -> Result<usize> {
+    let value = REPORT_TYPE | mouse.format.report_id as u16;
+    let report = mouse.format.build(data);
+
+    write_report(mouse, REQUEST_TYPE, value, mouse.interface as u16, &report)
+}
+
+// Sends an already built report, printing it on a dry run or when verbose
+fn write_report<T: UsbContext>(mouse: &mut Mouse<T>, request_type: u8, value: u16, index: u16, report: &[u8]) -> Result<usize> {
+    if mouse.dry_run || mouse.verbose {
+        print_report(report);
+    }
+    if mouse.dry_run {
+        return Ok(report.len());
+    }
+    if mouse.confirm_writes && !confirm(report, mouse.verbose) {
+        status!("Skipped");
+        return Ok(0);
+    }
+    if mouse.stats.transfers > 0 {
+        std::thread::sleep(mouse.delay);
+    }
+    let timeout = mouse.timeout_write;
+    let start = Instant::now();
+    let result = mouse.handle().write_control(request_type, REQUEST, value, index, report, timeout);
+    output::transfer("out", 0, start.elapsed(), &result);
+    let written = result
+        .inspect_err(|e| {
+            if mouse.report_raw_on_error && *e != Error::NoDevice {
+                print_error_report(mouse);
+            }
+        })?;
+
+    mouse.stats.transfers += 1;
+    mouse.stats.bytes += written;
+    mouse.sent.extend_from_slice(&report[..written]);
 
-    let data: [u8; 17] = [
-        0x08, 0x07, 0x00, 0x00, 0x0c + profile * 4, 0x04, lo, lo, hi * 0x44, ((0x55 - 2*lo as i16  - 0x44*hi as i16) & 0xFF) as u8, 0x00, 0x00, 0x00, 0x00, 0x00,
-        0x00, checksum as u8
-    ];
+    Ok(written)
+}
+
+// Asks whether to send the report, anything but y or yes declines
+fn confirm(report: &[u8], printed: bool) -> bool {
+    if !printed {
+        print_report(report);
+    }
+    output::prompt("Send this report? [y/N]");
+
+    let mut answer = String::new();
+    if std::io::stdin().read_line(&mut answer).is_err() {
+        return false;
+    }
 
-    write_set_report(handle, data)
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
 }
 
-fn write_set_report<T: UsbContext>(handle: &mut DeviceHandle<T>, data: [u8; 17]) -> Result<usize> {
-    let timeout = Duration::from_secs(1);
+// Prints a report in hex along with its checksum and the sum it was computed from
+fn print_report(report: &[u8]) {
+    let hex = report.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+    let sum = protocol::report_sum(report);
+    let checksum = report[report.len() - 1];
 
-    const REQUEST_TYPE: u8 = 0x21;
-    const REQUEST: u8 = 0x09;
-    const VALUE: u16 = 0x0208;
-    const INDEX: u16 = 0x0001;
+    data!("Report:   {}", hex);
+    data!(
+        "Checksum: {:#04x} (sum of the other bytes {:#04x}, {:#04x} - sum = {:#04x})",
+        checksum,
+        sum,
+        protocol::CHECKSUM_SUM,
+        protocol::CHECKSUM_SUM.wrapping_sub(sum)
+    );
+}
 
-    handle.write_control(REQUEST_TYPE, REQUEST, VALUE, INDEX, &data, timeout)
+// Prints what the device sends on the report interface's interrupt endpoint after a failed transfer
+fn print_error_report<T: UsbContext>(mouse: &mut Mouse<T>) {
+    let device = mouse.handle().device();
+    let endpoint = device.active_config_descriptor().ok().and_then(|config| {
+        config
+            .interfaces()
+            .filter(|i| i.number() == mouse.interface)
+            .flat_map(|i| i.descriptors())
+            .flat_map(|d| d.endpoint_descriptors().collect::<Vec<_>>())
+            .find(|e| e.direction() == Direction::In && e.transfer_type() == TransferType::Interrupt)
+            .map(|e| e.address())
+    });
+    let Some(address) = endpoint else {
+        warning!("Warning: interface {} has no interrupt IN endpoint to read a status from", mouse.interface);
+        return;
+    };
+
+    let timeout = mouse.timeout_read;
+    let start = Instant::now();
+    let result = read_interrupt(mouse.handle(), address, timeout);
+    output::transfer("in", address, start.elapsed(), &result.as_ref().map(Vec::len).map_err(|e| *e));
+    match result {
+        Ok(report) if !report.is_empty() => {
+            let hex = report.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+            match protocol::parse_report(&mouse.format, &report) {
+                Ok(_) => output::info(format!("Device answered on endpoint {:#04x}: {}", address, hex)),
+                Err(e) => warning!("Warning: {} on endpoint {:#04x}: {}", e, address, hex),
+            }
+        },
+        Ok(_) | Err(Error::Timeout) => output::info(format!("Device sent nothing on endpoint {:#04x}", address)),
+        Err(e) => warning!("Warning: failed to read endpoint {:#04x}: {}", address, e),
+    }
 }
 
-fn read_interrupt<T: UsbContext>(handle: &mut DeviceHandle<T>, address: u8) -> Result<Vec<u8>> {
-    let timeout = Duration::from_secs(1);
-    let mut buf = [0u8; 64];
+// Reads an interrupt report, doubling the buffer up to READ_MAX when the device sends more than fits
+fn read_interrupt<T: UsbContext>(handle: &mut DeviceHandle<T>, address: u8, timeout: Duration) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; 64];
 
-    handle
-        .read_interrupt(address, &mut buf, timeout)
-        .map(|_| buf.to_vec())
+    loop {
+        match handle.read_interrupt(address, &mut buf, timeout) {
+            Ok(n) => {
+                buf.truncate(n);
+                return Ok(buf);
+            }
+            Err(Error::Overflow) if buf.len() < READ_MAX => buf.resize(buf.len() * 2, 0),
+            Err(e) => return Err(e),
+        }
+    }
 }
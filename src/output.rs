@@ -0,0 +1,166 @@
+// Colored terminal output, colors are only used when the stream is a TTY and NO_COLOR isn't set.
+// With --log-format json, diagnostics go to stderr as JSON lines instead.
+
+use std::fmt::Display;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use owo_colors::{OwoColorize, Stream};
+use serde_json::json;
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable messages
+    Text,
+    /// One JSON object per line on stderr, including a line per transfer
+    Json,
+}
+
+// Set by --log-format json
+static JSON: AtomicBool = AtomicBool::new(false);
+
+// Set by --quiet, or by modes where stdout carries machine-readable output only
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+// Set in RPC mode, where stdout only carries responses
+static RESPONSES_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_responses_only(responses_only: bool) {
+    RESPONSES_ONLY.store(responses_only, Ordering::Relaxed);
+}
+
+// Prints requested output such as report dumps, to stderr when stdout only carries responses
+pub fn data(msg: impl Display) {
+    if RESPONSES_ONLY.load(Ordering::Relaxed) {
+        if is_json() {
+            return log("data", &msg.to_string());
+        }
+        eprintln!("{}", msg);
+    } else {
+        println!("{}", msg);
+    }
+}
+
+pub fn set_log_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Relaxed);
+}
+
+pub fn is_json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn log(level: &str, msg: &str) {
+    eprintln!("{}", json!({ "level": level, "message": msg }));
+}
+
+pub fn error(msg: impl Display) {
+    if is_json() {
+        return log("error", &msg.to_string());
+    }
+    eprintln!("{}", msg.if_supports_color(Stream::Stderr, |t| t.red()));
+}
+
+pub fn warning(msg: impl Display) {
+    if is_json() {
+        let msg = msg.to_string();
+        return log("warning", msg.strip_prefix("Warning: ").unwrap_or(&msg));
+    }
+    eprintln!("{}", msg.if_supports_color(Stream::Stderr, |t| t.yellow()));
+}
+
+// Diagnostic requested by a flag, printed to stderr even when quiet
+pub fn info(msg: impl Display) {
+    if is_json() {
+        return log("info", &msg.to_string());
+    }
+    eprintln!("{}", msg);
+}
+
+// Asks a question on stderr, the answer is read from stdin on the same line in text mode
+pub fn prompt(msg: impl Display) {
+    if is_json() {
+        return log("prompt", &msg.to_string());
+    }
+    eprint!("{} ", msg);
+    let _ = std::io::stderr().flush();
+}
+
+pub fn status(msg: impl Display) {
+    if is_quiet() {
+        return;
+    }
+    if is_json() {
+        return log("info", &msg.to_string());
+    }
+    println!("{}", msg);
+}
+
+// Logs a USB transfer, only in JSON mode as the text output already prints reports with --verbose
+pub fn transfer(direction: &str, endpoint: u8, duration: Duration, result: &rusb::Result<usize>) {
+    if !is_json() {
+        return;
+    }
+    let (bytes, error) = match result {
+        Ok(bytes) => (Some(*bytes), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    eprintln!(
+        "{}",
+        json!({
+            "level": "debug",
+            "event": "transfer",
+            "direction": direction,
+            "endpoint": endpoint,
+            "bytes": bytes,
+            "duration_ms": duration.as_secs_f64() * 1000.0,
+            "result": if error.is_none() { "ok" } else { "error" },
+            "error": error,
+        })
+    );
+}
+
+pub fn success(msg: impl Display) {
+    if is_quiet() {
+        return;
+    }
+    if is_json() {
+        return log("info", &msg.to_string());
+    }
+    println!("{}", msg.if_supports_color(Stream::Stdout, |t| t.green()));
+}
+
+// Prints a formatted error message to stderr
+macro_rules! error {
+    ($($arg:tt)*) => { $crate::output::error(format!($($arg)*)) };
+}
+
+// Prints a formatted warning to stderr
+macro_rules! warning {
+    ($($arg:tt)*) => { $crate::output::warning(format!($($arg)*)) };
+}
+
+// Prints a formatted success message to stdout, unless quiet
+macro_rules! success {
+    ($($arg:tt)*) => { $crate::output::success(format!($($arg)*)) };
+}
+
+// Prints formatted requested output, see data
+macro_rules! data {
+    ($($arg:tt)*) => { $crate::output::data(format!($($arg)*)) };
+}
+
+// Prints a formatted progress message to stdout, unless quiet
+macro_rules! status {
+    ($($arg:tt)*) => { $crate::output::status(format!($($arg)*)) };
+}
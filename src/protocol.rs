@@ -0,0 +1,440 @@
+//! Pure encoders for the feature reports understood by the mouse.
+//!
+//! Every encoder returns the exact bytes sent in the `SET_REPORT` control transfer, the last
+//! byte being the checksum. None of them touch USB so they can be reused by other tools.
+//!
+//! The checksum is chosen so that every byte of a report, checksum included, sums to
+//! [`CHECKSUM_SUM`] modulo 256.
+
+use std::num::{IntErrorKind, ParseIntError};
+use std::str::FromStr;
+
+use clap::ValueEnum;
+
+/// Length of the reports built by the encoders, the checksum being the last byte
+pub const REPORT_LEN: usize = 17;
+/// Longest report a firmware may expect, the size of a full-speed control packet
+pub const MAX_REPORT_LEN: usize = 64;
+/// Report ID leading every report built by the encoders
+pub const REPORT_ID: u8 = 0x08;
+/// Value every byte of a report sums to, modulo 256
+pub const CHECKSUM_SUM: u8 = 0x55;
+
+/// Lowest DPI accepted by the sensor
+pub const MIN_DPI: u16 = 50;
+/// Highest DPI accepted by the sensor
+pub const MAX_DPI: u16 = 26000;
+
+/// Named DPI values accepted wherever a DPI is expected
+pub const DPI_PRESETS: &[(&str, u16)] = &[
+    ("low", 800),
+    ("medium", 1600),
+    ("high", 3200),
+    ("max", MAX_DPI),
+];
+
+/// A DPI value, parsed from a decimal number, a `0x` prefixed hexadecimal number or one of the
+/// [`DPI_PRESETS`] names. Numbers too large for a u16 saturate, being out of range either way
+/// they are reported by [`Dpi::check`] or brought back by [`Dpi::clamp`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dpi(pub u16);
+
+impl FromStr for Dpi {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            return parse_saturating(hex, 16).map_err(|e| format!("invalid hexadecimal DPI '{}': {}", s, e));
+        }
+        if let Ok(value) = parse_saturating(s, 10) {
+            return Ok(value);
+        }
+
+        DPI_PRESETS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+            .map(|&(_, value)| Dpi(value))
+            .ok_or_else(|| {
+                let names = DPI_PRESETS.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(", ");
+                format!("'{}' is neither a number nor a preset ({})", s, names)
+            })
+    }
+}
+
+fn parse_saturating(s: &str, radix: u32) -> Result<Dpi, ParseIntError> {
+    match u64::from_str_radix(s, radix) {
+        Ok(value) => Ok(Dpi(u16::try_from(value).unwrap_or(u16::MAX))),
+        Err(e) if *e.kind() == IntErrorKind::PosOverflow => Ok(Dpi(u16::MAX)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Checks `profile` is a valid index for a model with `max_profiles` profiles
+pub fn check_profile(profile: u8, max_profiles: u8) -> Result<(), String> {
+    if profile >= max_profiles {
+        return Err(format!("Profile must be in range [0;{}]", max_profiles.saturating_sub(1)));
+    }
+
+    Ok(())
+}
+
+/// Checks `count` is a valid amount of active profiles for a model with `max_profiles` profiles
+/// Checks a report length, reports can't be shorter than what the encoders produce
+pub fn check_report_len(len: usize) -> Result<(), String> {
+    if !(REPORT_LEN..=MAX_REPORT_LEN).contains(&len) {
+        return Err(format!("Report length must be in range [{};{}]", REPORT_LEN, MAX_REPORT_LEN));
+    }
+
+    Ok(())
+}
+
+pub fn check_profiles_count(count: u8, max_profiles: u8) -> Result<(), String> {
+    if !(1..=max_profiles).contains(&count) {
+        return Err(format!(
+            "Count must be in range [1;{}], this model supports at most {} profiles",
+            max_profiles, max_profiles
+        ));
+    }
+
+    Ok(())
+}
+
+impl Dpi {
+    /// Brings the value within [`MIN_DPI`] and [`MAX_DPI`], it is still rounded down to a
+    /// multiple of 50 when encoded
+    pub fn clamp(self) -> Dpi {
+        Dpi(self.0.clamp(MIN_DPI, MAX_DPI))
+    }
+
+    /// Checks the value is within [`MIN_DPI`] and [`MAX_DPI`]
+    pub fn check(self) -> Result<(), String> {
+        if !(MIN_DPI..=MAX_DPI).contains(&self.0) {
+            return Err(format!(
+                "DPI value must be in range [{};{}] it will be rounded down to a multiple of 50",
+                MIN_DPI, MAX_DPI
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A device setting addressable by name, used by the generic `feature-set` command
+pub struct Setting {
+    /// Name the setting is looked up by
+    pub name: &'static str,
+    /// One line description shown when listing settings
+    pub description: &'static str,
+    /// Whether the setting belongs to a single profile
+    pub per_profile: bool,
+    /// Parses the value given on the command line
+    pub parse: fn(&str) -> Result<u16, String>,
+    /// Range checks a parsed value given the profile and the amount of profiles of the model
+    pub check: fn(profile: u8, value: u16, max_profiles: u8) -> Result<(), String>,
+    /// Builds the report for a parsed value
+    pub encode: fn(profile: u8, value: u16) -> [u8; REPORT_LEN],
+}
+
+/// Every setting that can be written by name, new settings register here
+pub const SETTINGS: &[Setting] = &[
+    Setting {
+        name: "dpi",
+        description: "DPI of a profile",
+        per_profile: true,
+        parse: |s| s.parse::<Dpi>().map(|dpi| dpi.0),
+        check: |profile, value, max_profiles| check_profile(profile, max_profiles).and(Dpi(value).check()),
+        encode: |profile, value| encode_set_dpi(profile, value),
+    },
+    Setting {
+        name: "profile",
+        description: "Active DPI profile",
+        per_profile: false,
+        parse: parse_byte,
+        check: |_, value, max_profiles| check_profile(value as u8, max_profiles),
+        encode: |_, value| encode_switch_profile(value as u8),
+    },
+    Setting {
+        name: "profiles",
+        description: "Amount of activated DPI profiles",
+        per_profile: false,
+        parse: parse_byte,
+        check: |_, value, max_profiles| check_profiles_count(value as u8, max_profiles),
+        encode: |_, value| encode_profiles_count(value as u8),
+    },
+];
+
+/// Looks a setting up by name
+pub fn find_setting(name: &str) -> Option<&'static Setting> {
+    SETTINGS.iter().find(|setting| setting.name.eq_ignore_ascii_case(name))
+}
+
+fn parse_byte(s: &str) -> Result<u16, String> {
+    s.parse::<u8>().map(u16::from).map_err(|e| format!("invalid value '{}': {}", s, e))
+}
+
+/// Builds the report switching the active DPI profile, `profile` must be in range [0;3]
+///
+/// Like every encoder, out of range inputs don't panic but produce a report the firmware will
+/// likely reject, which is what out-of-spec experiments rely on.
+pub fn encode_switch_profile(profile: u8) -> [u8; REPORT_LEN] {
+    [
+        0x08, 0x07, 0x00, 0x00, 0x04, 0x02, profile, 0x55u8.wrapping_sub(profile), 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xeb,
+    ]
+}
+
+/// Builds the report setting how many DPI profiles are activated, `count` must be in range [1;4]
+pub fn encode_profiles_count(count: u8) -> [u8; REPORT_LEN] {
+    [
+        0x08, 0x07, 0x00, 0x00, 0x02, 0x02, count, 0x55u8.wrapping_sub(count), 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0xed,
+    ]
+}
+
+/// Builds the report setting a profile's DPI, `profile` must be in range [0;3] and `dpi` in
+/// range [50;26000], it is rounded down to a multiple of 50
+pub fn encode_set_dpi(profile: u8, dpi: u16) -> [u8; REPORT_LEN] {
+    let dpi_index: u16 = (dpi / 50).wrapping_sub(1);
+    let lo: u8 = dpi_index as u8 ;
+    let hi: u8 = (dpi_index >> 8) as u8;
+    let checksum = 0x155u16.wrapping_sub(0x13 + (0x0c + profile as u16 * 4) + 0x55);
+
+    [
+        0x08, 0x07, 0x00, 0x00, 0x0cu8.wrapping_add(profile.wrapping_mul(4)), 0x04, lo, lo, hi.wrapping_mul(0x44), ((0x55 - 2*lo as i16  - 0x44*hi as i16) & 0xFF) as u8, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, checksum as u8
+    ]
+}
+
+/// How the last byte of a report is computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Checksum {
+    /// The reverse-engineered algorithm used by the vendor software, all bytes sum to
+    /// [`CHECKSUM_SUM`]
+    #[default]
+    Device,
+    /// Sum of every other byte, truncated to 8 bits
+    Sum,
+    /// Always zero
+    None,
+}
+
+impl Checksum {
+    /// Replaces the checksum computed by the encoders according to the strategy
+    pub fn apply(self, mut data: [u8; REPORT_LEN]) -> [u8; REPORT_LEN] {
+        data[REPORT_LEN - 1] = match self {
+            // Recomputed rather than kept from the encoder, as the report ID may have been replaced
+            Checksum::Device => CHECKSUM_SUM.wrapping_sub(report_sum(&data)),
+            Checksum::Sum => report_sum(&data),
+            Checksum::None => 0,
+        };
+
+        data
+    }
+}
+
+/// Shape of the reports expected by a given firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReportFormat {
+    /// Length the reports are zero padded to
+    pub len: usize,
+    /// Strategy used to compute the last byte
+    pub checksum: Checksum,
+    /// Leading byte of the reports
+    pub report_id: u8,
+}
+
+impl Default for ReportFormat {
+    fn default() -> Self {
+        ReportFormat { len: REPORT_LEN, checksum: Checksum::Device, report_id: REPORT_ID }
+    }
+}
+
+impl ReportFormat {
+    /// Turns an encoded report into the exact bytes to send, the report ID is replaced before
+    /// the checksum is computed
+    pub fn build(&self, mut data: [u8; REPORT_LEN]) -> Vec<u8> {
+        data[0] = self.report_id;
+        pad_report(self.checksum.apply(data), self.len)
+    }
+}
+
+/// Sums every byte of a report but the last one (the checksum), truncated to 8 bits
+pub fn report_sum(report: &[u8]) -> u8 {
+    let Some((_, bytes)) = report.split_last() else {
+        return 0;
+    };
+
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Validates a report read back from the mouse against the firmware's `format` before any field
+/// is extracted from it, firmware sometimes answers with fewer bytes than requested
+pub fn parse_report<'a>(format: &ReportFormat, buf: &'a [u8]) -> Result<&'a [u8], String> {
+    if buf.len() < format.len {
+        return Err(format!("Malformed report: got {} bytes, expected at least {}", buf.len(), format.len));
+    }
+    if buf[0] != format.report_id {
+        return Err(format!("Malformed report: starts with {:#04x}, expected {:#04x}", buf[0], format.report_id));
+    }
+
+    Ok(buf)
+}
+
+/// Zero pads a report to the firmware's report length, keeping the checksum as the last byte
+pub fn pad_report(data: [u8; REPORT_LEN], report_len: usize) -> Vec<u8> {
+    let report_len = report_len.max(REPORT_LEN);
+    let mut report = vec![0u8; report_len];
+
+    report[..REPORT_LEN - 1].copy_from_slice(&data[..REPORT_LEN - 1]);
+    report[report_len - 1] = data[REPORT_LEN - 1];
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_report_invariants(data: [u8; REPORT_LEN]) {
+        assert_eq!(data[0], REPORT_ID);
+        assert_eq!(data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), CHECKSUM_SUM, "{:02x?}", data);
+
+        let padded = ReportFormat { len: 64, ..Default::default() }.build(data);
+        assert_eq!(padded.len(), 64);
+        assert_eq!(padded[63], data[REPORT_LEN - 1]);
+        assert_eq!(padded.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), CHECKSUM_SUM);
+    }
+
+    // The input space is small enough to check every valid input rather than sampling it
+    #[test]
+    fn encoders_invariants() {
+        for profile in 0..4 {
+            assert_report_invariants(encode_switch_profile(profile));
+            for dpi in MIN_DPI..=MAX_DPI {
+                assert_report_invariants(encode_set_dpi(profile, dpi));
+            }
+        }
+        for count in 1..=4 {
+            assert_report_invariants(encode_profiles_count(count));
+        }
+    }
+
+    #[test]
+    fn out_of_range_inputs_dont_panic() {
+        encode_switch_profile(u8::MAX);
+        encode_profiles_count(u8::MAX);
+        encode_set_dpi(u8::MAX, 0);
+        encode_set_dpi(u8::MAX, u16::MAX);
+    }
+
+    #[test]
+    fn settings_match_encoders() {
+        let dpi = find_setting("dpi").unwrap();
+        assert_eq!((dpi.encode)(2, (dpi.parse)("high").unwrap()), encode_set_dpi(2, 3200));
+
+        let profile = find_setting("profile").unwrap();
+        assert!((profile.check)(0, (profile.parse)("4").unwrap(), 4).is_err());
+        assert_eq!((profile.encode)(0, 3), encode_switch_profile(3));
+
+        assert!(find_setting("unknown").is_none());
+    }
+
+    #[test]
+    fn malformed_reports() {
+        let format = ReportFormat::default();
+        let report = format.build(encode_switch_profile(1));
+
+        assert_eq!(parse_report(&format, &report), Ok(&report[..]));
+        assert!(parse_report(&format, &[]).is_err());
+        assert!(parse_report(&format, &report[..REPORT_LEN - 1]).is_err());
+        assert!(parse_report(&format, &[0u8; REPORT_LEN]).is_err());
+
+        // Longer firmwares need the whole padded report and may use another report ID
+        let format = ReportFormat { len: 33, report_id: 0x09, ..format };
+        let padded = format.build(encode_switch_profile(1));
+        assert_eq!(parse_report(&format, &padded), Ok(&padded[..]));
+        assert!(parse_report(&format, &padded[..REPORT_LEN]).is_err());
+        assert!(parse_report(&format, &report).is_err());
+    }
+
+    #[test]
+    fn profile_boundaries() {
+        assert!(check_profile(0, 4).is_ok());
+        assert!(check_profile(3, 4).is_ok());
+        assert!(check_profile(4, 4).is_err());
+        assert!(check_profile(255, 4).is_err());
+    }
+
+    #[test]
+    fn profiles_count_boundaries() {
+        assert!(check_profiles_count(0, 4).is_err());
+        assert!(check_profiles_count(1, 4).is_ok());
+        assert!(check_profiles_count(4, 4).is_ok());
+        assert!(check_profiles_count(5, 4).is_err());
+    }
+
+    #[test]
+    fn dpi_parsing() {
+        assert_eq!("1600".parse(), Ok(Dpi(1600)));
+        assert_eq!("0x640".parse(), Ok(Dpi(1600)));
+        assert_eq!("high".parse(), Ok(Dpi(3200)));
+        assert!("0xzz".parse::<Dpi>().is_err());
+        // Oversized values are out of range rather than invalid
+        assert_eq!("70000".parse(), Ok(Dpi(u16::MAX)));
+        assert_eq!("0x11170".parse(), Ok(Dpi(u16::MAX)));
+        assert_eq!("99999999999999999999999".parse(), Ok(Dpi(u16::MAX)));
+        assert!("70000".parse::<Dpi>().unwrap().check().is_err());
+        assert_eq!("70000".parse::<Dpi>().unwrap().clamp(), Dpi(MAX_DPI));
+    }
+
+    #[test]
+    fn dpi_clamping() {
+        assert_eq!(Dpi(10).clamp(), Dpi(MIN_DPI));
+        assert_eq!(Dpi(1600).clamp(), Dpi(1600));
+        assert_eq!(Dpi(30000).clamp(), Dpi(MAX_DPI));
+    }
+
+    #[test]
+    fn dpi_boundaries() {
+        assert!(Dpi(49).check().is_err());
+        assert!(Dpi(50).check().is_ok());
+        assert!(Dpi(26000).check().is_ok());
+        assert!(Dpi(26001).check().is_err());
+    }
+
+    // The DPI is sent as (dpi / 50) - 1, so any value is rounded down to a multiple of 50
+    #[test]
+    fn dpi_rounding() {
+        // (dpi, rounded dpi, lo, hi)
+        let cases = [(51, 50, 0x00, 0x00), (99, 50, 0x00, 0x00), (100, 100, 0x01, 0x00), (26000, 26000, 0x07, 0x02)];
+        for (dpi, rounded, lo, hi) in cases {
+            let report = encode_set_dpi(0, dpi);
+            assert_eq!((report[6], report[7], report[8]), (lo, lo, hi * 0x44), "dpi {}", dpi);
+            assert_eq!(report, encode_set_dpi(0, rounded), "dpi {}", dpi);
+        }
+    }
+
+    #[test]
+    fn checksum_follows_report_id() {
+        let format = ReportFormat { report_id: 0x09, ..Default::default() };
+        let report = format.build(encode_set_dpi(0, 1600));
+
+        assert_eq!(report[0], 0x09);
+        assert_eq!(report.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), CHECKSUM_SUM);
+    }
+
+    #[test]
+    fn padded_reports_keep_checksum() {
+        assert!(check_report_len(REPORT_LEN - 1).is_err());
+        assert!(check_report_len(MAX_REPORT_LEN + 1).is_err());
+
+        for len in REPORT_LEN..=MAX_REPORT_LEN {
+            assert!(check_report_len(len).is_ok());
+            let format = ReportFormat { len, report_id: 0x09, ..Default::default() };
+            let report = format.build(encode_set_dpi(1, 1600));
+
+            assert_eq!(report.len(), len);
+            assert_eq!(report.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)), CHECKSUM_SUM, "len {}", len);
+        }
+    }
+}
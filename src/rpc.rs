@@ -0,0 +1,156 @@
+// Newline-delimited JSON-RPC 2.0 over stdin/stdout, keeping the device open for the session
+
+use std::io::{BufRead, Write};
+
+use rusb::{Error, Result, UsbContext};
+use rustar::protocol::{self, Dpi};
+use serde::Deserialize;
+use serde_json::{Value, json};
+
+use crate::Mouse;
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+pub(crate) const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+// Implementation defined range, used for failed USB transfers
+pub(crate) const DEVICE_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+    jsonrpc: String,
+    // Requests without an ID are notifications and get no response
+    #[serde(default)]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+pub(crate) struct RpcError {
+    pub(crate) code: i64,
+    pub(crate) message: String,
+    // The failed transfer's error, for DEVICE_ERROR
+    pub(crate) usb: Option<Error>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        RpcError { code, message: message.into(), usb: None }
+    }
+}
+
+impl From<Error> for RpcError {
+    fn from(e: Error) -> Self {
+        RpcError { code: DEVICE_ERROR, message: e.to_string(), usb: Some(e) }
+    }
+}
+
+// Serves requests until stdin is closed, stops early only when the mouse is unplugged
+pub fn serve<T: UsbContext>(mouse: &mut Mouse<T>) -> Result<()> {
+    let stdin = std::io::stdin();
+    let mut stdout = std::io::stdout();
+
+    for line in stdin.lock().lines() {
+        let line = line.map_err(|_| Error::Io)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (id, result) = match serde_json::from_str::<Request>(&line) {
+            Ok(request) if request.jsonrpc != "2.0" => {
+                (request.id, Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")))
+            },
+            Ok(request) => {
+                let result = call(mouse, &request.method, &request.params);
+                // Notifications never get a response, a failure is only logged
+                if request.id.is_none() {
+                    match result {
+                        Err(RpcError { usb: Some(Error::NoDevice), .. }) => return Err(Error::NoDevice),
+                        Err(e) => error!("Notification {} failed: {}", request.method, e.message),
+                        Ok(_) => {},
+                    }
+                    continue;
+                }
+                (request.id, result)
+            },
+            Err(e) => (None, Err(RpcError::new(PARSE_ERROR, e.to_string()))),
+        };
+
+        let disconnected = matches!(&result, Err(RpcError { usb: Some(Error::NoDevice), .. }));
+        let response = match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(e) => json!({ "jsonrpc": "2.0", "id": id, "error": { "code": e.code, "message": e.message } }),
+        };
+        writeln!(stdout, "{}", response).map_err(|_| Error::Io)?;
+        stdout.flush().map_err(|_| Error::Io)?;
+
+        if disconnected {
+            return Err(Error::NoDevice);
+        }
+    }
+
+    Ok(())
+}
+
+// Runs a single method, also used by the HTTP API so both share the CLI's validation
+pub(crate) fn call<T: UsbContext>(
+    mouse: &mut Mouse<T>,
+    method: &str,
+    params: &Value,
+) -> std::result::Result<Value, RpcError> {
+    let max_profiles = mouse.max_profiles;
+    let no_validate = mouse.no_validate;
+    let check = |result: std::result::Result<(), String>| match result {
+        Err(e) if !no_validate => Err(RpcError::new(INVALID_PARAMS, e)),
+        _ => Ok(()),
+    };
+
+    let written = match method {
+        "setDpi" => {
+            let profile = param_u8(params, "profile")?;
+            let dpi = crate::clamp_dpi(param_dpi(params)?, mouse.clamp);
+            check(protocol::check_profile(profile, max_profiles))?;
+            check(dpi.check())?;
+
+            crate::set_profile_dpi(mouse, profile, dpi.0)
+        },
+        "selectProfile" => {
+            let profile = param_u8(params, "profile")?;
+            check(protocol::check_profile(profile, max_profiles))?;
+
+            crate::switch_profile(mouse, profile)
+        },
+        "setProfilesCount" => {
+            let count = param_u8(params, "count")?;
+            check(protocol::check_profiles_count(count, max_profiles))?;
+
+            crate::set_profiles_count(mouse, count)
+        },
+        _ => return Err(RpcError::new(METHOD_NOT_FOUND, format!("Unknown method '{}'", method))),
+    };
+
+    Ok(json!({ "written": written? }))
+}
+
+fn param_u8(params: &Value, name: &str) -> std::result::Result<u8, RpcError> {
+    params
+        .get(name)
+        .and_then(Value::as_u64)
+        .and_then(|value| u8::try_from(value).ok())
+        .ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("'{}' must be an integer in range [0;255]", name)))
+}
+
+// The DPI is either a number or a string accepted by the Dpi parser (preset name, hexadecimal)
+fn param_dpi(params: &Value) -> std::result::Result<Dpi, RpcError> {
+    let invalid = |message: String| RpcError::new(INVALID_PARAMS, message);
+
+    match params.get("dpi") {
+        Some(Value::Number(n)) => n
+            .as_u64()
+            .map(|value| Dpi(u16::try_from(value).unwrap_or(u16::MAX)))
+            .ok_or_else(|| invalid("'dpi' must be a non-negative integer".to_string())),
+        Some(Value::String(s)) => s.parse().map_err(invalid),
+        _ => Err(invalid("'dpi' is required".to_string())),
+    }
+}
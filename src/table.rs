@@ -0,0 +1,53 @@
+// Renders tabular command output as aligned columns or CSV
+
+use clap::ValueEnum;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// Columns aligned with spaces
+    Table,
+    /// Comma separated values with a header line
+    Csv,
+}
+
+pub fn print(format: Format, headers: &[&str], rows: &[Vec<String>]) {
+    match format {
+        Format::Table => print_table(headers, rows),
+        Format::Csv => print_csv(headers, rows),
+    }
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let line = |cells: Vec<&str>| {
+        let padded: Vec<String> = cells.iter().zip(&widths).map(|(cell, &width)| format!("{:<width$}", cell)).collect();
+        println!("{}", padded.join("  ").trim_end());
+    };
+
+    line(headers.to_vec());
+    for row in rows {
+        line(row.iter().map(String::as_str).collect());
+    }
+}
+
+fn print_csv(headers: &[&str], rows: &[Vec<String>]) {
+    println!("{}", headers.iter().map(|h| csv_field(h)).collect::<Vec<_>>().join(","));
+    for row in rows {
+        println!("{}", row.iter().map(|cell| csv_field(cell)).collect::<Vec<_>>().join(","));
+    }
+}
+
+// Quotes fields containing separators, quotes or line breaks
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
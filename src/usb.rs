@@ -0,0 +1,244 @@
+//! Interface claiming with exact kernel driver bookkeeping, undone when dropped.
+//!
+//! The USB calls go through the [`Interfaces`] trait so the bookkeeping can be tested without
+//! a device.
+
+use std::ops::{Deref, DerefMut};
+
+use rusb::{DeviceHandle, Result, UsbContext};
+
+/// The subset of a device handle used to claim and release interfaces
+pub trait Interfaces {
+    fn kernel_driver_active(&mut self, iface: u8) -> Result<bool>;
+    fn detach_kernel_driver(&mut self, iface: u8) -> Result<()>;
+    fn attach_kernel_driver(&mut self, iface: u8) -> Result<()>;
+    fn claim_interface(&mut self, iface: u8) -> Result<()>;
+    fn release_interface(&mut self, iface: u8) -> Result<()>;
+}
+
+impl<T: UsbContext> Interfaces for DeviceHandle<T> {
+    fn kernel_driver_active(&mut self, iface: u8) -> Result<bool> {
+        DeviceHandle::kernel_driver_active(self, iface)
+    }
+
+    fn detach_kernel_driver(&mut self, iface: u8) -> Result<()> {
+        DeviceHandle::detach_kernel_driver(self, iface)
+    }
+
+    fn attach_kernel_driver(&mut self, iface: u8) -> Result<()> {
+        DeviceHandle::attach_kernel_driver(self, iface)
+    }
+
+    fn claim_interface(&mut self, iface: u8) -> Result<()> {
+        DeviceHandle::claim_interface(self, iface)
+    }
+
+    fn release_interface(&mut self, iface: u8) -> Result<()> {
+        DeviceHandle::release_interface(self, iface)
+    }
+}
+
+/// A handle along with the interfaces claimed on it. Dropping it releases the interfaces and
+/// re-attaches the kernel driver to exactly the interfaces it was detached from, so a claimed
+/// device can't be leaked by forgetting the cleanup.
+pub struct Claimed<H: Interfaces> {
+    handle: H,
+    claimed: Vec<u8>,
+    detached: Vec<u8>,
+    reattach: bool,
+}
+
+impl<H: Interfaces> Claimed<H> {
+    /// Wraps a handle without claiming anything yet
+    pub fn new(handle: H) -> Self {
+        Claimed { handle, claimed: Vec::new(), detached: Vec::new(), reattach: true }
+    }
+
+    /// Claims the interfaces, detaching the kernel driver first from those it is bound to when
+    /// `detach` is set. Whatever was done before a failure is still undone on release.
+    pub fn claim(&mut self, interfaces: &[u8], detach: bool) -> Result<()> {
+        for &iface in interfaces {
+            // Platforms without kernel driver support report an error here, there's nothing to detach then
+            if detach && self.handle.kernel_driver_active(iface).unwrap_or(false) {
+                self.handle.detach_kernel_driver(iface)?;
+                self.detached.push(iface);
+            }
+        }
+        for &iface in interfaces {
+            self.handle.claim_interface(iface)?;
+            self.claimed.push(iface);
+        }
+
+        Ok(())
+    }
+
+    /// Whether the kernel driver is re-attached on release, true by default
+    pub fn set_reattach(&mut self, reattach: bool) {
+        self.reattach = reattach;
+    }
+
+    /// Interfaces the kernel driver was detached from
+    pub fn detached(&self) -> &[u8] {
+        &self.detached
+    }
+
+    /// Forgets the claimed interfaces and detached drivers so neither releasing nor dropping
+    /// undoes anything, leaving the device as it is
+    pub fn leave(&mut self) {
+        self.claimed.clear();
+        self.detached.clear();
+    }
+
+    /// Releases the interfaces and re-attaches the kernel driver now, reporting the first error
+    /// which dropping would ignore. Every interface is handled even if one of them fails.
+    pub fn release(&mut self) -> Result<()> {
+        let mut result = Ok(());
+
+        for iface in std::mem::take(&mut self.claimed) {
+            result = result.and(self.handle.release_interface(iface));
+        }
+        let detached = std::mem::take(&mut self.detached);
+        if self.reattach {
+            for iface in detached {
+                result = result.and(self.handle.attach_kernel_driver(iface));
+            }
+        }
+
+        result
+    }
+}
+
+impl<H: Interfaces> Drop for Claimed<H> {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+impl<H: Interfaces> Deref for Claimed<H> {
+    type Target = H;
+
+    fn deref(&self) -> &H {
+        &self.handle
+    }
+}
+
+impl<H: Interfaces> DerefMut for Claimed<H> {
+    fn deref_mut(&mut self) -> &mut H {
+        &mut self.handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    type Calls = Rc<RefCell<Vec<(&'static str, u8)>>>;
+
+    // Records every call, interfaces listed in `bound` have an active kernel driver
+    struct MockHandle {
+        bound: Vec<u8>,
+        calls: Calls,
+    }
+
+    impl MockHandle {
+        fn new(bound: &[u8]) -> (Self, Calls) {
+            let calls = Calls::default();
+            (MockHandle { bound: bound.to_vec(), calls: calls.clone() }, calls)
+        }
+    }
+
+    impl Interfaces for MockHandle {
+        fn kernel_driver_active(&mut self, iface: u8) -> Result<bool> {
+            Ok(self.bound.contains(&iface))
+        }
+
+        fn detach_kernel_driver(&mut self, iface: u8) -> Result<()> {
+            self.calls.borrow_mut().push(("detach", iface));
+            Ok(())
+        }
+
+        fn attach_kernel_driver(&mut self, iface: u8) -> Result<()> {
+            self.calls.borrow_mut().push(("attach", iface));
+            Ok(())
+        }
+
+        fn claim_interface(&mut self, iface: u8) -> Result<()> {
+            self.calls.borrow_mut().push(("claim", iface));
+            Ok(())
+        }
+
+        fn release_interface(&mut self, iface: u8) -> Result<()> {
+            self.calls.borrow_mut().push(("release", iface));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reattaches_only_detached_interfaces() {
+        let (handle, calls) = MockHandle::new(&[1]);
+        let mut claimed = Claimed::new(handle);
+
+        claimed.claim(&[0, 1], true).unwrap();
+        assert_eq!(claimed.detached(), &[1]);
+
+        claimed.release().unwrap();
+        assert_eq!(
+            *calls.borrow(),
+            vec![("detach", 1), ("claim", 0), ("claim", 1), ("release", 0), ("release", 1), ("attach", 1)]
+        );
+    }
+
+    #[test]
+    fn no_detach_leaves_drivers_alone() {
+        let (handle, calls) = MockHandle::new(&[0, 1]);
+        let mut claimed = Claimed::new(handle);
+
+        claimed.claim(&[0, 1], false).unwrap();
+        claimed.release().unwrap();
+
+        assert!(calls.borrow().iter().all(|(call, _)| *call != "detach" && *call != "attach"));
+    }
+
+    #[test]
+    fn drop_releases_and_reattaches() {
+        let (handle, calls) = MockHandle::new(&[0]);
+
+        let mut claimed = Claimed::new(handle);
+        claimed.claim(&[0, 1], true).unwrap();
+        drop(claimed);
+
+        assert_eq!(
+            *calls.borrow(),
+            vec![("detach", 0), ("claim", 0), ("claim", 1), ("release", 0), ("release", 1), ("attach", 0)]
+        );
+    }
+
+    #[test]
+    fn drop_after_release_does_nothing() {
+        let (handle, calls) = MockHandle::new(&[0]);
+
+        let mut claimed = Claimed::new(handle);
+        claimed.claim(&[0], true).unwrap();
+        claimed.release().unwrap();
+        let count = calls.borrow().len();
+        drop(claimed);
+
+        assert_eq!(calls.borrow().len(), count);
+    }
+
+    #[test]
+    fn leave_undoes_nothing() {
+        let (handle, calls) = MockHandle::new(&[0, 1]);
+        let mut claimed = Claimed::new(handle);
+
+        claimed.claim(&[0, 1], true).unwrap();
+        claimed.leave();
+        claimed.release().unwrap();
+        drop(claimed);
+
+        assert_eq!(*calls.borrow(), vec![("detach", 0), ("detach", 1), ("claim", 0), ("claim", 1)]);
+    }
+}
@@ -0,0 +1,31 @@
+// Runs the binary without hardware, --allow-absent prints the reports when no mouse is found
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[test]
+fn rpc_stderr_is_json_lines() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_rustar"))
+        .args(["--log-format", "json", "--dry-run", "--allow-absent", "--stats", "rpc"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"selectProfile\",\"params\":{\"profile\":1}}\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("\"data\""), "report dump missing from {}", stderr);
+    for line in stderr.lines() {
+        assert!(serde_json::from_str::<serde_json::Value>(line).is_ok(), "not JSON: {}", line);
+    }
+    for line in String::from_utf8(output.stdout).unwrap().lines() {
+        assert!(serde_json::from_str::<serde_json::Value>(line).is_ok(), "not JSON: {}", line);
+    }
+}